@@ -0,0 +1,292 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tracing::error;
+
+use crate::{
+    config::{AccessLogConfig, LogDestination, LogFormat},
+    forwarded::Client,
+    request::Request,
+};
+
+/// What is known about a request when it starts, kept until its response is sent.
+#[derive(Debug, Clone)]
+pub struct RequestLog {
+    /// The client behind trusted proxies, or the peer.
+    client: Client,
+    request_id: String,
+    time: DateTime<Utc>,
+    started: Instant,
+    /// `None` when the request could not be parsed.
+    request: Option<RequestSummary>,
+    route: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct RequestSummary {
+    method: String,
+    path: String,
+    version: String,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    body_len: usize,
+}
+
+impl RequestLog {
+    pub fn new(client: Client, request_id: &str, request: Option<&Request>) -> Self {
+        let request = request.map(|request| RequestSummary {
+            method: format!("{:?}", request.method()),
+            path: request.request_line().path().to_string(),
+            version: request.request_line().version().to_string(),
+            user_agent: request.headers().get("User-Agent").cloned(),
+            referer: request.headers().get("Referer").cloned(),
+            body_len: request.body().map_or(0, |body| body.len()),
+        });
+
+        RequestLog {
+            client,
+            request_id: request_id.to_string(),
+            time: Utc::now(),
+            started: Instant::now(),
+            request,
+            route: None,
+        }
+    }
+
+    pub fn set_route(&mut self, route: Option<String>) {
+        self.route = route;
+    }
+
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    pub fn method(&self) -> Option<&str> {
+        self.request.as_ref().map(|request| request.method.as_str())
+    }
+
+    /// Size of the request body.
+    pub fn bytes_in(&self) -> usize {
+        self.request.as_ref().map_or(0, |request| request.body_len)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Writes one line per request, in the configured format, from a dedicated thread
+/// so that slow disks never stall connections.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: LogFormat,
+    lines: Option<mpsc::Sender<String>>,
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig) -> Result<AccessLog> {
+        let mut writer = match &config.destination {
+            LogDestination::Off => {
+                return Ok(AccessLog {
+                    format: config.format,
+                    lines: None,
+                })
+            }
+            LogDestination::Stdout => LogWriter::Stdout,
+            LogDestination::File(path) => {
+                LogWriter::File(RotatingFile::open(path, config.max_size, config.max_files)?)
+            }
+        };
+
+        let (lines, received) = mpsc::channel::<String>();
+
+        thread::spawn(move || {
+            for line in received {
+                if let Err(e) = writer.write_line(&line) {
+                    error!("Error writing access log: {:#}", e);
+                }
+            }
+        });
+
+        Ok(AccessLog {
+            format: config.format,
+            lines: Some(lines),
+        })
+    }
+
+    /// Logs a request once its response was sent. `bytes` is the size of the response body.
+    pub fn log(&self, request_log: &RequestLog, status: u16, bytes: usize) {
+        let Some(lines) = &self.lines else {
+            return;
+        };
+
+        // The request ID comes last, so that parsers of the standard formats still work
+        let line = match self.format {
+            LogFormat::Common => format!(
+                "{} \"{}\"",
+                self.common(request_log, status, bytes),
+                escape(&request_log.request_id)
+            ),
+            LogFormat::Combined => {
+                let request = request_log.request.as_ref();
+                let referer = request.and_then(|request| request.referer.as_deref());
+                let user_agent = request.and_then(|request| request.user_agent.as_deref());
+
+                format!(
+                    "{} \"{}\" \"{}\" \"{}\"",
+                    self.common(request_log, status, bytes),
+                    escape(referer.unwrap_or("-")),
+                    escape(user_agent.unwrap_or("-")),
+                    escape(&request_log.request_id)
+                )
+            }
+            LogFormat::Json => Self::json(request_log, status, bytes),
+        };
+
+        // The thread only stops once every sender is gone
+        let _ = lines.send(line);
+    }
+
+    fn common(&self, request_log: &RequestLog, status: u16, bytes: usize) -> String {
+        let request_line = match &request_log.request {
+            Some(request) => escape(&format!(
+                "{} {} {}",
+                request.method, request.path, request.version
+            )),
+            None => "-".to_string(),
+        };
+
+        // CLF writes "-" instead of 0 for empty bodies
+        let bytes = match bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+
+        format!(
+            "{} - - [{}] \"{}\" {} {}",
+            request_log.client.ip,
+            request_log.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            request_line,
+            status,
+            bytes
+        )
+    }
+
+    fn json(request_log: &RequestLog, status: u16, bytes: usize) -> String {
+        let request = request_log.request.as_ref();
+
+        json!({
+            "time": request_log.time.to_rfc3339(),
+            "remote_addr": request_log.client.ip.to_string(),
+            "scheme": request_log.client.scheme,
+            "request_id": request_log.request_id,
+            "method": request.map(|request| &request.method),
+            "path": request.map(|request| &request.path),
+            "protocol": request.map(|request| &request.version),
+            "status": status,
+            "bytes": bytes,
+            "duration_ms": request_log.started.elapsed().as_secs_f64() * 1000.0,
+            "user_agent": request.and_then(|request| request.user_agent.as_ref()),
+            "referer": request.and_then(|request| request.referer.as_ref()),
+        })
+        .to_string()
+    }
+}
+
+/// Escapes quotes, backslashes and control characters, so that a client cannot forge
+/// log lines through its request line or headers.
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+enum LogWriter {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl LogWriter {
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        match self {
+            LogWriter::Stdout => {
+                writeln!(io::stdout().lock(), "{}", line)?;
+                Ok(())
+            }
+            LogWriter::File(file) => file.write_line(line),
+        }
+    }
+}
+
+/// A log file renamed to `<path>.1` once it reaches `max_size` bytes, shifting older files
+/// up to `<path>.<max_files>`, past which they are deleted.
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: usize) -> Result<RotatingFile> {
+        let file = Self::open_file(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn open_file(path: &str) -> Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open access log {}", path))
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = format!("{}.{}", self.path, index);
+
+                if fs::metadata(&from).is_ok() {
+                    fs::rename(&from, format!("{}.{}", self.path, index + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
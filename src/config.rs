@@ -1,30 +1,961 @@
-use anyhow::anyhow;
-use std::{env, fs};
+//! Server configuration, read from a TOML file, the environment and command line flags.
+//!
+//! Later sources override earlier ones:
+//!
+//! 1. built-in defaults,
+//! 2. the file passed with `--config`,
+//! 3. the `HTTP_PORT` and `RUST_LOG` environment variables,
+//! 4. command line flags.
+//!
+//! Every key of the file is optional:
+//!
+//! ```toml
+//! [server]
+//! bind = ["0.0.0.0", "::"]   # or one address, e.g. "::", which also accepts IPv4 clients
+//! port = 8080
+//! unix_socket = "/run/http.sock"
+//! unix_socket_mode = 0o660
+//! directory = "public"        # relative paths are resolved from the file's directory
+//! shutdown_timeout = 30
+//! pre_stop_delay = 0          # seconds not ready but still accepting, before the shutdown
+//! metrics_path = "/metrics"   # off by default, as metrics reveal the server's traffic
+//! health_routes = true
+//! trusted_proxies = ["10.0.0.0/8", "::1"]   # whose X-Forwarded-* and Forwarded are believed
+//! proxy_protocol = false      # connections from trusted proxies start with a PROXY header
+//!
+//! [tls]
+//! port = 8443
+//! cert = "cert.pem"
+//! key = "key.pem"
+//! sni = [{ name = "example.com", cert = "example.pem", key = "example-key.pem" }]
+//!
+//! [timeouts]                  # in seconds
+//! idle = 60
+//! header = 10
+//! body = 30
+//! handler = 30
+//! write = 30
+//!
+//! [limits]
+//! max_connections = 10000     # 0 lifts the limit
+//! max_connections_per_ip = 0
+//! policy = "stop"             # queue, reject or stop
+//! max_queued_connections = 1024   # under the queue policy, 0 lifts the limit
+//! max_head_size = 65536       # bytes of a request line and headers
+//! max_body_size = 10485760    # bytes of a request body
+//! decode_request_bodies = false   # inflate gzip and deflate uploads for the handlers
+//! max_decoded_body_size = 67108864
+//!
+//! [rate_limit]
+//! limit = 100
+//! window = 60
+//! key = "ip"                  # ip, route or header:<name>
+//!
+//! [cors]
+//! origins = ["https://app.example.com", "https://*.example.com", "regex:^https://pr-[0-9]+\\.example\\.dev$"]
+//! methods = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+//! headers = ["*"]             # those requested by the browser, or a list
+//! expose_headers = ["X-Request-Id"]
+//! credentials = false
+//! max_age = 600
+//!
+//! [access_log]
+//! destination = "stdout"      # stdout, off or a file path
+//! format = "combined"         # common, combined or json
+//! max_size = 10485760
+//! max_files = 5
+//!
+//! [logging]
+//! level = "info"
+//! json = false
+//!
+//! [[routes]]
+//! path = "/assets"
+//! directory = "assets"
+//!
+//! [[routes]]
+//! path = "/api"
+//! proxy = ["http://127.0.0.1:3000", "http://127.0.0.1:3001"]   # taking turns
+//! health_check = "/healthz"   # upstreams failing it are skipped
+//! health_interval = 10
+//!
+//! [[hosts]]                   # requests for other hosts go to the routes above
+//! names = ["example.com", "*.example.com"]
+//! directory = "example"       # defaults to server.directory
+//!
+//! [[hosts.routes]]
+//! path = "/assets"
+//! directory = "example/assets"
+//! ```
+
+use anyhow::{anyhow, Context};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    env, fmt, fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    forwarded::Cidr,
+    listener, proxy,
+    request::{canonical_header_name, HTTPMethod},
+    vhost,
+};
+
+const USAGE: &str = "\
+Usage: http-server-starter-rust [OPTIONS]
+
+Options:
+  -p, --port <PORT>                  Port to listen on [env: HTTP_PORT] [default: 4221]
+      --bind <ADDRESS>               IP address to listen on, repeatable
+                                     [default: 127.0.0.1, none with --unix-socket or
+                                     sockets passed by systemd]
+      --unix-socket <PATH>           Unix socket to listen on
+      --unix-socket-mode <MODE>      Permissions of the Unix socket in octal, e.g. 660
+      --trusted-proxy <CIDR>         Proxy whose forwarding headers are believed, repeatable,
+                                     e.g. 10.0.0.0/8, or 0.0.0.0 for Unix socket clients
+      --proxy-protocol <on|off>      Connections start with a PROXY protocol header, sent by
+                                     a trusted proxy [default: off]
+      --directory <DIR>              Directory of the /files routes [default: ./public]
+      --config <FILE>                TOML config file, overridden by the environment and flags
+      --shutdown-timeout <SECONDS>   Time given to in-flight requests on shutdown [default: 30]
+      --pre-stop-delay <SECONDS>     Time /readyz fails before the shutdown starts, while
+                                     connections are still accepted [default: 0]
+  -h, --help                         Print this help
+  -V, --version                      Print the version
+
+TLS:
+      --tls-cert <FILE>              Certificate chain in PEM format
+      --tls-key <FILE>               Private key in PEM format
+      --tls-port <PORT>              Separate HTTPS port, TLS is detected on --port otherwise
+      --tls-sni <NAME,CERT,KEY>      Certificate for a server name, repeatable
+
+Timeouts, in seconds:
+      --idle-timeout <SECONDS>       Wait for a new request [default: 60]
+      --header-timeout <SECONDS>     Read a request head or TLS handshake [default: 10]
+      --body-timeout <SECONDS>       Read a request body [default: 30]
+      --handler-timeout <SECONDS>    Run a handler [default: 30]
+      --write-timeout <SECONDS>      Write each part of a response [default: 30]
+
+Limits:
+      --max-connections <COUNT>      Concurrent connections, 0 for no limit [default: 10000]
+      --max-connections-per-ip <COUNT>
+                                     Concurrent connections per client, 0 for no limit [default: 0]
+      --connection-limit-policy <queue|reject|stop>
+                                     Handling of connections over the limit [default: stop]
+      --max-queued-connections <COUNT>
+                                     Connections waiting under the queue policy, rejected
+                                     past it, 0 for no limit [default: 1024]
+      --max-head-size <BYTES>        Request line and headers, larger ones are answered
+                                     with 431 [default: 65536]
+      --max-body-size <BYTES>        Request body, larger ones are answered with 413
+                                     [default: 10485760]
+      --decode-request-bodies <on|off>
+                                     Inflate gzip and deflate request bodies [default: off]
+      --max-decoded-body-size <BYTES>
+                                     Inflated request body, larger ones are answered with 413
+                                     [default: 67108864]
+      --rate-limit <COUNT>           Requests per window, 0 for no limit [default: 0]
+      --rate-limit-window <SECONDS>  [default: 60]
+      --rate-limit-key <ip|route|header:NAME>
+                                     What requests are limited by [default: ip]
+
+CORS:
+      --cors-origin <ORIGIN>         Origin allowed to call the server, repeatable: an origin,
+                                     *, https://*.example.com or regex:<PATTERN>
+      --cors-methods <METHODS>       Allowed methods, comma-separated
+                                     [default: GET,HEAD,POST,PUT,PATCH,DELETE]
+      --cors-headers <HEADERS>       Allowed request headers, comma-separated, or * for those
+                                     requested [default: *]
+      --cors-expose-headers <HEADERS>
+                                     Response headers readable by scripts, comma-separated
+      --cors-credentials <on|off>    Allow cookies and authorization headers [default: off]
+      --cors-max-age <SECONDS>       How long browsers cache preflight responses [default: 600]
+
+Logging:
+      --access-log <stdout|off|FILE> [default: stdout]
+      --access-log-format <common|combined|json>
+                                     [default: combined]
+      --access-log-max-size <BYTES>  Size at which the access log is rotated [default: 10485760]
+      --access-log-max-files <COUNT> Rotated access logs kept [default: 5]
+      --log-level <FILTER>           Diagnostics filter [env: RUST_LOG] [default: info]
+      --log-json                     Write diagnostics as JSON
+
+Endpoints:
+      --metrics-path <PATH|off>      Path of the Prometheus metrics, e.g. /metrics
+                                     [default: off]
+      --health-routes <on|off>       Serve /healthz and /readyz [default: on]
+
+Signals:
+  SIGINT, SIGTERM                    Shut down gracefully, immediately when sent twice
+  SIGHUP                             Reload the TLS certificates
+  SIGUSR2                            Start the binary again, handing the sockets over;
+                                     systemd units need NotifyAccess=all
+";
+
+/// Flags taking no value.
+const SWITCHES: [&str; 5] = ["-h", "--help", "-V", "--version", "--log-json"];
+
+/// An invalid command line, as opposed to an invalid config file or environment, for
+/// usage errors to exit with their own status.
+#[derive(Debug)]
+pub struct ArgError(anyhow::Error);
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for ArgError {}
+
+/// What the command line asks for.
+pub enum Command {
+    Run(Box<Config>),
+    PrintHelp,
+    PrintVersion,
+}
+
+impl Command {
+    /// Parses the arguments as returned by `env::args`, the program name first.
+    pub fn parse(args: impl Iterator<Item = String>) -> crate::Result<Command> {
+        let args = Self::split_values(args.skip(1));
+
+        let flags = Self::flags(&args);
+
+        // Help wins over any other flag, even an invalid one
+        if flags.iter().any(|flag| *flag == "-h" || *flag == "--help") {
+            return Ok(Command::PrintHelp);
+        }
+        if flags
+            .iter()
+            .any(|flag| *flag == "-V" || *flag == "--version")
+        {
+            return Ok(Command::PrintVersion);
+        }
+
+        Config::new(args.into_iter()).map(|config| Command::Run(Box::new(config)))
+    }
+
+    /// The arguments in flag position, leaving out the values of options, so that
+    /// `--directory -h` serves a directory named `-h` rather than printing the help.
+    fn flags(args: &[String]) -> Vec<&str> {
+        let mut flags = Vec::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg.starts_with('-') && !SWITCHES.contains(&arg.as_str()) {
+                args.next();
+            }
+
+            flags.push(arg.as_str());
+        }
+
+        flags
+    }
+
+    /// Splits `--flag=value` arguments into the flag and its value.
+    fn split_values(args: impl Iterator<Item = String>) -> Vec<String> {
+        args.flat_map(|arg| match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                vec![flag.to_string(), value.to_string()]
+            }
+            _ => vec![arg],
+        })
+        .collect()
+    }
+
+    pub fn usage() -> &'static str {
+        USAGE
+    }
+
+    pub fn version() -> String {
+        format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    }
+}
 
 pub struct Config {
+    /// Addresses listened on, each on `port` and on the TLS port if any.
+    pub bind: Vec<IpAddr>,
     pub port: u16,
+    /// Unix socket listened on, in addition to the addresses of `bind`.
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix socket file. The umask applies when unset.
+    pub unix_socket_mode: Option<u32>,
     pub pub_dir: String,
+    pub tls: Option<TlsConfig>,
+    /// How long in-flight requests may take to complete once a shutdown starts.
+    pub shutdown_timeout: Duration,
+    /// How long the server keeps accepting connections once asked to stop, while `/readyz`
+    /// already reports it as not ready, for load balancers to stop sending it traffic.
+    pub pre_stop_delay: Duration,
+    pub timeouts: Timeouts,
+    pub request_limits: RequestLimits,
+    pub connection_limits: ConnectionLimits,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Cross-origin requests allowed, none when unset.
+    pub cors: Option<CorsConfig>,
+    pub access_log: AccessLogConfig,
+    pub logging: LoggingConfig,
+    /// Path serving the metrics in the Prometheus format, if any. Off unless configured.
+    pub metrics_path: Option<String>,
+    /// Whether `/healthz` and `/readyz` are served.
+    pub health_routes: bool,
+    /// Proxies whose `Forwarded` and `X-Forwarded-*` headers tell the client of requests.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Whether connections start with a PROXY protocol header.
+    pub proxy_protocol: bool,
+    pub routes: Vec<RouteConfig>,
+    /// Virtual hosts, the other settings applying to the default host.
+    pub hosts: Vec<HostConfig>,
+}
+
+/// Limits on how long each step of serving a request may take, so that slow or idle
+/// clients cannot hold connections open indefinitely.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// How long a connection may wait for the first byte of a request.
+    pub idle: Duration,
+    /// How long the request head, or the TLS handshake, may take once the client started it.
+    pub header_read: Duration,
+    pub body_read: Duration,
+    pub handler: Duration,
+    /// How long the client may take to accept each write of the response.
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            idle: Duration::from_secs(60),
+            header_read: Duration::from_secs(10),
+            body_read: Duration::from_secs(30),
+            handler: Duration::from_secs(30),
+            write: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Caps on the size of requests, so that clients cannot make the server buffer arbitrarily
+/// large ones.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Bytes of the request line and headers, past which requests are answered with 431.
+    pub max_head_size: usize,
+    /// Bytes of the body, past which requests are answered with 413 before it is read.
+    pub max_body_size: usize,
+    /// Whether compressed request bodies are inflated before reaching the handlers.
+    pub decode_bodies: bool,
+    /// Bytes of an inflated body, past which requests are answered with 413, so that a small
+    /// compressed body cannot expand into an arbitrarily large one.
+    pub max_decoded_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_head_size: 64 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            decode_bodies: false,
+            max_decoded_body_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Caps on concurrent connections, protecting the server from running out of memory
+/// or file descriptors under load.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    /// Connections over the per-IP cap are always rejected, whatever the policy.
+    pub max_per_ip: Option<usize>,
+    /// Connections waiting for a slot under the queue policy, past which they are rejected.
+    pub max_queued: Option<usize>,
+    pub policy: LimitPolicy,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: Some(10_000),
+            max_per_ip: None,
+            max_queued: Some(1024),
+            policy: LimitPolicy::StopAccepting,
+        }
+    }
+}
+
+/// What happens to new connections once `max_connections` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Accept them, but only serve them once a connection closes. Those over
+    /// `max_queued` are rejected.
+    Queue,
+    /// Answer them with a 503 response and close them.
+    Reject,
+    /// Leave them in the listen backlog until a connection closes.
+    StopAccepting,
+}
+
+/// Allows `limit` requests per `window` for every value of `key`.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub limit: u32,
+    pub window: Duration,
+    pub key: RateLimitKey,
+}
+
+/// What requests are grouped by for rate limiting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitKey {
+    ClientIp,
+    /// The value of a header such as an API key, falling back to the client IP without it.
+    Header(String),
+    /// The method and route pattern, e.g. `GET /files/:filename`, limiting every client
+    /// together.
+    Route,
+}
+
+/// Which cross-origin requests browsers are allowed to make.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub origins: Vec<CorsOrigin>,
+    pub methods: Vec<String>,
+    /// Request headers allowed, those a preflight asks for when `None`.
+    pub headers: Option<Vec<String>>,
+    pub expose_headers: Vec<String>,
+    /// Whether requests may carry cookies and authorization headers.
+    pub credentials: bool,
+    pub max_age: Duration,
+}
+
+/// An origin allowed to make cross-origin requests.
+#[derive(Debug, Clone)]
+pub enum CorsOrigin {
+    /// `*`, any origin.
+    Any,
+    /// An origin like `https://app.example.com`.
+    Exact(String),
+    /// An origin like `https://*.example.com`, matching any subdomain of `example.com`.
+    Wildcard { prefix: String, suffix: String },
+    /// `regex:<pattern>`, matching the whole origin.
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub destination: LogDestination,
+    pub format: LogFormat,
+    /// Size in bytes at which a log file is rotated.
+    pub max_size: u64,
+    /// Number of rotated files kept next to the current one.
+    pub max_files: usize,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            destination: LogDestination::Stdout,
+            format: LogFormat::Combined,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogDestination {
+    Off,
+    Stdout,
+    File(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The Common Log Format of Apache and nginx, followed by the request ID.
+    Common,
+    /// The Common Log Format followed by the referer, user agent and request ID.
+    Combined,
+    /// One JSON object per line, also carrying the request ID and latency.
+    Json,
+}
+
+/// Diagnostics verbosity and format, separate from the access log.
+#[derive(Debug, Clone)]
+pub struct LoggingConfig {
+    /// A filter in the `RUST_LOG` syntax, e.g. `warn` or `info,http_server_starter_rust=debug`.
+    pub filter: String,
+    /// Writes one JSON object per event instead of human-readable lines.
+    pub json: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            filter: "info".to_string(),
+            json: false,
+        }
+    }
+}
+
+/// Certificate and key locations for HTTPS termination.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Port serving HTTPS. When unset, or equal to `port`, TLS is detected on the plain port.
+    pub port: Option<u16>,
+    pub cert_path: String,
+    pub key_path: String,
+    /// Additional certificates selected by SNI server name, as `(server name, cert, key)`.
+    pub sni: Vec<(String, String, String)>,
+}
+
+/// A route served from the configuration rather than by a handler in code.
+#[derive(Debug, Clone)]
+pub struct RouteConfig {
+    /// Path prefix of the route, without a trailing slash, e.g. `/assets`.
+    pub path: String,
+    pub target: RouteTarget,
+}
+
+#[derive(Debug, Clone)]
+pub enum RouteTarget {
+    /// Files under a directory, e.g. `/assets/app.js` served from `<directory>/app.js`.
+    Directory(String),
+    /// Requests forwarded to upstream servers, e.g. `/api/users` to `<upstream>/api/users`.
+    Proxy(ProxyConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Upstream servers as `host:port`, taking requests in turn.
+    pub upstreams: Vec<String>,
+    /// Path requested from every upstream, those failing being skipped until they pass again.
+    pub health_check: Option<String>,
+    pub health_interval: Duration,
+}
+
+/// A virtual host, serving requests whose `Host` header matches one of its names.
+#[derive(Debug, Clone)]
+pub struct HostConfig {
+    /// Names like `example.com`, or `*.example.com` for its subdomains.
+    pub names: Vec<String>,
+    /// Public directory of the host, the server's one when unset.
+    pub pub_dir: Option<String>,
+    pub routes: Vec<RouteConfig>,
 }
 
 impl Config {
-    pub fn new(mut args: impl Iterator<Item = String>) -> crate::Result<Self> {
-        let mut port = Self::parse_port_from_env()?;
-        let mut pub_dir = format!("{}/public", env::current_dir().unwrap().to_string_lossy());
+    /// Builds the configuration from command line flags, without the program name.
+    pub fn new(args: impl Iterator<Item = String>) -> crate::Result<Self> {
+        let args = args.collect::<Vec<String>>();
+
+        let file = match Self::find_config_path(&args).map_err(ArgError)? {
+            Some(path) => ConfigFile::load(&path)?,
+            None => ConfigFile::default(),
+        };
+
+        let mut bind = file
+            .server
+            .bind
+            .clone()
+            .map(OneOrMany::into_vec)
+            .unwrap_or_default();
+        let mut cli_bind = Vec::new();
+        let mut unix_socket = file
+            .server
+            .unix_socket
+            .as_ref()
+            .map(|path| PathBuf::from(file.resolve(path)));
+        let mut unix_socket_mode = match file.server.unix_socket_mode {
+            Some(mode) => Some(
+                Self::match_mode(Some(format!("{:o}", mode)))
+                    .with_context(|| file.invalid("server.unix_socket_mode"))?,
+            ),
+            None => None,
+        };
+        let mut port = match Self::parse_port_from_env()? {
+            Some(port) => port,
+            None => file.server.port.unwrap_or(4221),
+        };
+        let mut pub_dir = match &file.server.directory {
+            Some(dir) => file.dir("server.directory", dir)?,
+            None => {
+                let current_dir =
+                    env::current_dir().context("Cannot read the current directory")?;
+
+                current_dir.join("public").to_string_lossy().to_string()
+            }
+        };
+
+        let mut tls_port = file.tls.port;
+        let mut tls_cert = file
+            .tls
+            .cert
+            .as_ref()
+            .map(|cert| file.file("tls.cert", cert))
+            .transpose()?;
+        let mut tls_key = file
+            .tls
+            .key
+            .as_ref()
+            .map(|key| file.file("tls.key", key))
+            .transpose()?;
+        let mut tls_sni = file
+            .tls
+            .sni
+            .iter()
+            .enumerate()
+            .map(|(index, sni)| {
+                Ok((
+                    sni.name.to_ascii_lowercase(),
+                    file.file(&format!("tls.sni[{}].cert", index), &sni.cert)?,
+                    file.file(&format!("tls.sni[{}].key", index), &sni.key)?,
+                ))
+            })
+            .collect::<crate::Result<Vec<_>>>()?;
+
+        let mut shutdown_timeout = file
+            .server
+            .shutdown_timeout
+            .map_or(Duration::from_secs(30), Duration::from_secs);
+
+        let mut pre_stop_delay = file
+            .server
+            .pre_stop_delay
+            .map_or(Duration::ZERO, Duration::from_secs);
+
+        let mut timeouts = Timeouts::default();
+        file.timeouts.apply(&mut timeouts);
+
+        let mut request_limits = RequestLimits::default();
+        file.apply_request_limits(&mut request_limits);
+
+        let mut connection_limits = ConnectionLimits::default();
+        file.apply_limits(&mut connection_limits)?;
+
+        let mut rate_limit = file.rate_limit.limit.filter(|limit| *limit > 0);
+        let mut rate_limit_window = file
+            .rate_limit
+            .window
+            .map_or(Duration::from_secs(60), Duration::from_secs);
+        let mut rate_limit_key = match &file.rate_limit.key {
+            Some(key) => Self::match_rate_limit_key(Some(key.clone()))
+                .with_context(|| file.invalid("rate_limit.key"))?,
+            None => RateLimitKey::ClientIp,
+        };
+
+        let mut cors_origins = file
+            .cors
+            .origins
+            .iter()
+            .map(|origin| Self::match_cors_origin(Some(origin.clone())))
+            .collect::<crate::Result<Vec<_>>>()
+            .with_context(|| file.invalid("cors.origins"))?;
+        let mut cli_cors_origins = Vec::new();
+        let mut cors_methods = match &file.cors.methods {
+            Some(methods) => Self::match_methods(Some(methods.join(",")))
+                .with_context(|| file.invalid("cors.methods"))?,
+            None => ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+        };
+        let mut cors_headers = match &file.cors.headers {
+            Some(headers) => Self::match_cors_headers(Some(headers.join(",")))
+                .with_context(|| file.invalid("cors.headers"))?,
+            None => None,
+        };
+        let mut cors_expose_headers = match &file.cors.expose_headers {
+            Some(headers) => Self::match_header_names(Some(headers.join(",")))
+                .with_context(|| file.invalid("cors.expose_headers"))?,
+            None => Vec::new(),
+        };
+        let mut cors_credentials = file.cors.credentials.unwrap_or(false);
+        let mut cors_max_age = Duration::from_secs(file.cors.max_age.unwrap_or(600));
+
+        let mut access_log = AccessLogConfig::default();
+        file.apply_access_log(&mut access_log)?;
+
+        let mut logging = LoggingConfig::default();
+        if let Some(level) = &file.logging.level {
+            logging.filter = level.clone();
+        }
+        if let Some(json) = file.logging.json {
+            logging.json = json;
+        }
+        if let Ok(filter) = env::var("RUST_LOG") {
+            logging.filter = filter;
+        }
+
+        let mut metrics_path = match &file.server.metrics_path {
+            Some(path) => Self::match_metrics_path(Some(path.clone()))
+                .with_context(|| file.invalid("server.metrics_path"))?,
+            None => None,
+        };
+        let mut health_routes = file.server.health_routes.unwrap_or(true);
+        let mut trusted_proxies = file
+            .server
+            .trusted_proxies
+            .iter()
+            .map(|cidr| Self::match_cidr(Some(cidr.clone())))
+            .collect::<crate::Result<Vec<_>>>()
+            .with_context(|| file.invalid("server.trusted_proxies"))?;
+        let mut cli_trusted_proxies = Vec::new();
+        let mut proxy_protocol = file.server.proxy_protocol.unwrap_or(false);
+
+        let routes = file.routes("routes", &file.route_sections)?;
+        let hosts = file.hosts()?;
+
+        let mut args = args.into_iter();
 
         while let Some(arg) = args.next() {
-            match arg.as_str() {
-                "-p" | "--port" => {
-                    port = Self::match_port(args.next())?;
-                }
-                "--directory" => {
-                    pub_dir = Self::match_dir(args.next())?;
+            // Errors of the arguments themselves, unlike those of the file
+            let mut apply = || -> crate::Result<()> {
+                match arg.as_str() {
+                    "--config" => {
+                        // Already loaded, as flags override the file whatever their position
+                        args.next();
+                    }
+                    "-p" | "--port" => {
+                        port = Self::match_port(args.next())?;
+                    }
+                    "--bind" => {
+                        cli_bind.push(Self::match_bind(args.next())?);
+                    }
+                    "--unix-socket" => {
+                        unix_socket = Some(PathBuf::from(
+                            args.next().ok_or(anyhow!("Unix socket path not found"))?,
+                        ));
+                    }
+                    "--unix-socket-mode" => {
+                        unix_socket_mode = Some(Self::match_mode(args.next())?);
+                    }
+                    "--directory" => {
+                        pub_dir = Self::match_dir(args.next())?;
+                    }
+                    "--tls-port" => {
+                        tls_port = Some(Self::match_port(args.next())?);
+                    }
+                    "--tls-cert" => {
+                        tls_cert = Some(Self::match_file(args.next())?);
+                    }
+                    "--tls-key" => {
+                        tls_key = Some(Self::match_file(args.next())?);
+                    }
+                    "--tls-sni" => {
+                        tls_sni.push(Self::match_sni(args.next())?);
+                    }
+                    "--shutdown-timeout" => {
+                        shutdown_timeout = Self::match_seconds(args.next())?;
+                    }
+                    "--pre-stop-delay" => {
+                        pre_stop_delay = Self::match_seconds(args.next())?;
+                    }
+                    "--idle-timeout" => {
+                        timeouts.idle = Self::match_seconds(args.next())?;
+                    }
+                    "--header-timeout" => {
+                        timeouts.header_read = Self::match_seconds(args.next())?;
+                    }
+                    "--body-timeout" => {
+                        timeouts.body_read = Self::match_seconds(args.next())?;
+                    }
+                    "--handler-timeout" => {
+                        timeouts.handler = Self::match_seconds(args.next())?;
+                    }
+                    "--write-timeout" => {
+                        timeouts.write = Self::match_seconds(args.next())?;
+                    }
+                    "--max-head-size" => {
+                        request_limits.max_head_size = Self::match_count(args.next())?;
+                    }
+                    "--max-body-size" => {
+                        request_limits.max_body_size = Self::match_count(args.next())?;
+                    }
+                    "--decode-request-bodies" => {
+                        request_limits.decode_bodies = Self::match_switch(args.next())?;
+                    }
+                    "--max-decoded-body-size" => {
+                        request_limits.max_decoded_body_size = Self::match_count(args.next())?;
+                    }
+                    "--max-connections" => {
+                        // 0 lifts the limit
+                        connection_limits.max_connections =
+                            Some(Self::match_count(args.next())?).filter(|max| *max > 0);
+                    }
+                    "--max-connections-per-ip" => {
+                        connection_limits.max_per_ip =
+                            Some(Self::match_count(args.next())?).filter(|max| *max > 0);
+                    }
+                    "--max-queued-connections" => {
+                        connection_limits.max_queued =
+                            Some(Self::match_count(args.next())?).filter(|max| *max > 0);
+                    }
+                    "--connection-limit-policy" => {
+                        connection_limits.policy = Self::match_policy(args.next())?;
+                    }
+                    "--rate-limit" => {
+                        rate_limit =
+                            Some(Self::match_count(args.next())?).filter(|limit| *limit > 0);
+                    }
+                    "--rate-limit-window" => {
+                        rate_limit_window = Self::match_seconds(args.next())?;
+                    }
+                    "--rate-limit-key" => {
+                        rate_limit_key = Self::match_rate_limit_key(args.next())?;
+                    }
+                    "--cors-origin" => {
+                        cli_cors_origins.push(Self::match_cors_origin(args.next())?);
+                    }
+                    "--cors-methods" => {
+                        cors_methods = Self::match_methods(args.next())?;
+                    }
+                    "--cors-headers" => {
+                        cors_headers = Self::match_cors_headers(args.next())?;
+                    }
+                    "--cors-expose-headers" => {
+                        cors_expose_headers = Self::match_header_names(args.next())?;
+                    }
+                    "--cors-credentials" => {
+                        cors_credentials = Self::match_switch(args.next())?;
+                    }
+                    "--cors-max-age" => {
+                        cors_max_age = Self::match_seconds(args.next())?;
+                    }
+                    "--access-log" => {
+                        access_log.destination = Self::match_log_destination(args.next())?;
+                    }
+                    "--access-log-format" => {
+                        access_log.format = Self::match_log_format(args.next())?;
+                    }
+                    "--access-log-max-size" => {
+                        access_log.max_size = Self::match_count(args.next())? as u64;
+                    }
+                    "--access-log-max-files" => {
+                        access_log.max_files = Self::match_count(args.next())?;
+                    }
+                    "--log-level" => {
+                        logging.filter = args.next().ok_or(anyhow!("Log level not found"))?;
+                    }
+                    "--log-json" => {
+                        logging.json = true;
+                    }
+                    "--metrics-path" => {
+                        metrics_path = Self::match_metrics_path(args.next())?;
+                    }
+                    "--health-routes" => {
+                        health_routes = Self::match_switch(args.next())?;
+                    }
+                    "--trusted-proxy" => {
+                        cli_trusted_proxies.push(Self::match_cidr(args.next())?);
+                    }
+                    "--proxy-protocol" => {
+                        proxy_protocol = Self::match_switch(args.next())?;
+                    }
+
+                    _ => return Err(anyhow!("Unknown argument: {}", arg)),
                 }
 
-                _ => {}
-            }
+                Ok(())
+            };
+
+            apply().map_err(ArgError)?;
+        }
+
+        // Flags replace the addresses of the file instead of adding to them
+        if !cli_bind.is_empty() {
+            bind = cli_bind;
+        }
+        if !cli_trusted_proxies.is_empty() {
+            trusted_proxies = cli_trusted_proxies;
+        }
+        if !cli_cors_origins.is_empty() {
+            cors_origins = cli_cors_origins;
+        }
+
+        // Anyone could claim any address otherwise
+        if proxy_protocol && trusted_proxies.is_empty() {
+            return Err(anyhow!(
+                "The PROXY protocol requires trusted proxies, set with --trusted-proxy"
+            ));
+        }
+
+        // A Unix socket, or sockets passed by a supervisor, replace the default address
+        // rather than adding to it
+        if bind.is_empty() && unix_socket.is_none() && !listener::is_socket_activated() {
+            bind.push(IpAddr::V4(Ipv4Addr::LOCALHOST));
         }
 
-        Ok(Self { port, pub_dir })
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                port: tls_port,
+                cert_path,
+                key_path,
+                sni: tls_sni,
+            }),
+            (None, None) if tls_port.is_none() && tls_sni.is_empty() => None,
+            _ => return Err(anyhow!("TLS requires both --tls-cert and --tls-key")),
+        };
+
+        if rate_limit_window.is_zero() {
+            return Err(anyhow!("The rate limit window cannot be 0"));
+        }
+
+        let rate_limit = rate_limit
+            .map(|limit| {
+                Ok::<_, anyhow::Error>(RateLimitConfig {
+                    limit: u32::try_from(limit).map_err(|_| anyhow!("Rate limit too large"))?,
+                    window: rate_limit_window,
+                    key: rate_limit_key,
+                })
+            })
+            .transpose()?;
+
+        // Any site could act on behalf of the users logged in otherwise
+        if cors_credentials
+            && cors_origins
+                .iter()
+                .any(|origin| matches!(origin, CorsOrigin::Any))
+        {
+            return Err(anyhow!(
+                "CORS credentials cannot be allowed for any origin, list the origins instead"
+            ));
+        }
+
+        let cors = (!cors_origins.is_empty()).then_some(CorsConfig {
+            origins: cors_origins,
+            methods: cors_methods,
+            headers: cors_headers,
+            expose_headers: cors_expose_headers,
+            credentials: cors_credentials,
+            max_age: cors_max_age,
+        });
+
+        Ok(Self {
+            bind,
+            port,
+            unix_socket,
+            unix_socket_mode,
+            pub_dir,
+            tls,
+            shutdown_timeout,
+            pre_stop_delay,
+            timeouts,
+            request_limits,
+            connection_limits,
+            rate_limit,
+            cors,
+            access_log,
+            logging,
+            metrics_path,
+            health_routes,
+            trusted_proxies,
+            proxy_protocol,
+            routes,
+            hosts,
+        })
     }
 
     fn match_port(port_arg: Option<String>) -> crate::Result<u16> {
@@ -33,19 +964,731 @@ impl Config {
         port.parse::<u16>().map_err(|_| anyhow!("Invalid PORT"))
     }
 
-    fn parse_port_from_env() -> crate::Result<u16> {
-        // If the HTTP_PORT environment variable is not set, use the default value of 4221
-        let port_str = env::var("HTTP_PORT").unwrap_or("4221".to_string());
+    fn parse_port_from_env() -> crate::Result<Option<u16>> {
+        match env::var("HTTP_PORT") {
+            Ok(port) => port
+                .parse::<u16>()
+                .map(Some)
+                .map_err(|_| anyhow!("Invalid HTTP_PORT")),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Finds the value of `--config`, which must be read before the other flags.
+    fn find_config_path(args: &[String]) -> crate::Result<Option<String>> {
+        match args.iter().position(|arg| arg == "--config") {
+            Some(index) => args
+                .get(index + 1)
+                .cloned()
+                .map(Some)
+                .ok_or(anyhow!("Config file path not found")),
+            None => Ok(None),
+        }
+    }
+
+    fn match_bind(bind: Option<String>) -> crate::Result<IpAddr> {
+        let bind = bind.ok_or(anyhow!("Bind address not found"))?;
+
+        bind.parse::<IpAddr>()
+            .map_err(|_| anyhow!("Invalid bind address: {}", bind))
+    }
+
+    fn match_cidr(cidr: Option<String>) -> crate::Result<Cidr> {
+        let cidr = cidr.ok_or(anyhow!("Trusted proxy not found"))?;
+
+        cidr.parse::<Cidr>()
+    }
+
+    fn match_mode(mode: Option<String>) -> crate::Result<u32> {
+        let mode = mode.ok_or(anyhow!("Socket mode not found"))?;
+
+        // Expected format: octal permissions, e.g. 660, 0660 or 0o660
+        match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+            Ok(mode) if mode <= 0o777 => Ok(mode),
+            _ => Err(anyhow!(
+                "Invalid socket mode, expected octal permissions like 660"
+            )),
+        }
+    }
+
+    fn match_seconds(seconds: Option<String>) -> crate::Result<Duration> {
+        let seconds = seconds.ok_or(anyhow!("Duration value not found"))?;
+
+        seconds
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|_| anyhow!("Invalid duration, expected a number of seconds"))
+    }
+
+    fn match_count(count: Option<String>) -> crate::Result<usize> {
+        let count = count.ok_or(anyhow!("Count value not found"))?;
 
-        port_str.parse::<u16>().map_err(|_| anyhow!("Invalid PORT"))
+        count
+            .parse::<usize>()
+            .map_err(|_| anyhow!("Invalid count: {}", count))
+    }
+
+    fn match_policy(policy: Option<String>) -> crate::Result<LimitPolicy> {
+        let policy = policy.ok_or(anyhow!("Policy value not found"))?;
+
+        match policy.as_str() {
+            "queue" => Ok(LimitPolicy::Queue),
+            "reject" => Ok(LimitPolicy::Reject),
+            "stop" => Ok(LimitPolicy::StopAccepting),
+            _ => Err(anyhow!(
+                "Invalid connection limit policy, expected queue, reject or stop"
+            )),
+        }
+    }
+
+    fn match_rate_limit_key(key: Option<String>) -> crate::Result<RateLimitKey> {
+        let key = key.ok_or(anyhow!("Rate limit key not found"))?;
+
+        // Expected format: ip, route or header:<name>
+        match key.split_once(':') {
+            Some(("header", name)) if !name.is_empty() => {
+                Ok(RateLimitKey::Header(canonical_header_name(name)))
+            }
+            None if key == "ip" => Ok(RateLimitKey::ClientIp),
+            None if key == "route" => Ok(RateLimitKey::Route),
+            _ => Err(anyhow!(
+                "Invalid rate limit key, expected ip, route or header:<name>"
+            )),
+        }
+    }
+
+    fn match_cors_origin(origin: Option<String>) -> crate::Result<CorsOrigin> {
+        let origin = origin.ok_or(anyhow!("CORS origin not found"))?;
+        let invalid = || {
+            anyhow!(
+                "Invalid CORS origin {}, expected e.g. https://app.example.com, \
+                 https://*.example.com, * or regex:<PATTERN>",
+                origin
+            )
+        };
+
+        if origin == "*" {
+            return Ok(CorsOrigin::Any);
+        }
+
+        if let Some(pattern) = origin.strip_prefix("regex:") {
+            let regex = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| anyhow!("Invalid CORS origin pattern {}: {}", pattern, e))?;
+
+            return Ok(CorsOrigin::Regex(regex));
+        }
+
+        // Origins are a scheme and a host, with an optional port, e.g. https://example.com:8443
+        let (scheme, host) = origin.split_once("://").ok_or_else(invalid)?;
+
+        if scheme.is_empty() || host.is_empty() || host.contains(['/', ' ']) {
+            return Err(invalid());
+        }
+
+        let origin = origin.to_ascii_lowercase();
+
+        match origin.split_once('*') {
+            None => Ok(CorsOrigin::Exact(origin)),
+            Some((prefix, suffix))
+                if prefix.ends_with("://") && suffix.starts_with('.') && !suffix.contains('*') =>
+            {
+                Ok(CorsOrigin::Wildcard {
+                    prefix: prefix.to_string(),
+                    suffix: suffix.to_string(),
+                })
+            }
+            Some(_) => Err(invalid()),
+        }
+    }
+
+    fn match_methods(methods: Option<String>) -> crate::Result<Vec<String>> {
+        let methods = methods.ok_or(anyhow!("Methods not found"))?;
+
+        methods
+            .split(',')
+            .map(|method| {
+                let method = method.trim().to_ascii_uppercase();
+
+                HTTPMethod::parse_method(&method)
+                    .map(|_| method.clone())
+                    .map_err(|_| anyhow!("Invalid method: {}", method))
+            })
+            .collect()
+    }
+
+    /// Parses header names, or `*` for any, returned as `None`.
+    fn match_cors_headers(headers: Option<String>) -> crate::Result<Option<Vec<String>>> {
+        match headers {
+            Some(headers) if headers.trim() == "*" => Ok(None),
+            headers => Self::match_header_names(headers).map(Some),
+        }
+    }
+
+    fn match_header_names(headers: Option<String>) -> crate::Result<Vec<String>> {
+        let headers = headers.ok_or(anyhow!("Headers not found"))?;
+
+        headers
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                if name.contains(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_')) {
+                    return Err(anyhow!("Invalid header name: {}", name));
+                }
+
+                Ok(canonical_header_name(name))
+            })
+            .collect()
+    }
+
+    fn match_log_destination(destination: Option<String>) -> crate::Result<LogDestination> {
+        let destination = destination.ok_or(anyhow!("Access log destination not found"))?;
+
+        // Expected format: stdout, off or a file path
+        match destination.as_str() {
+            "off" => Ok(LogDestination::Off),
+            "stdout" | "-" => Ok(LogDestination::Stdout),
+            path => Ok(LogDestination::File(path.to_string())),
+        }
+    }
+
+    fn match_log_format(format: Option<String>) -> crate::Result<LogFormat> {
+        let format = format.ok_or(anyhow!("Access log format not found"))?;
+
+        match format.as_str() {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!(
+                "Invalid access log format, expected common, combined or json"
+            )),
+        }
+    }
+
+    fn match_metrics_path(path: Option<String>) -> crate::Result<Option<String>> {
+        let path = path.ok_or(anyhow!("Metrics path not found"))?;
+
+        match path.as_str() {
+            "off" => Ok(None),
+            path if path.starts_with('/') && !path.contains(' ') => Ok(Some(path.to_string())),
+            _ => Err(anyhow!(
+                "Invalid metrics path, expected off or a path like /metrics"
+            )),
+        }
+    }
+
+    fn match_switch(value: Option<String>) -> crate::Result<bool> {
+        let value = value.ok_or(anyhow!("Switch value not found"))?;
+
+        match value.as_str() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(anyhow!("Invalid switch value, expected on or off")),
+        }
     }
 
     fn match_dir(dir: Option<String>) -> crate::Result<String> {
-        let path = dir.as_deref().unwrap_or("public");
+        let path = dir.ok_or(anyhow!("Directory path not found"))?;
 
         // If the directory does not exist, return an error
-        fs::canonicalize(path)
+        fs::canonicalize(&path)
+            .map(|path| path.to_string_lossy().to_string())
+            .map_err(|_| anyhow!("Invalid directory: {}", path))
+    }
+
+    fn match_file(file: Option<String>) -> crate::Result<String> {
+        let path = file.ok_or(anyhow!("File path not found"))?;
+
+        // Keep an absolute path, so certificates can be reloaded after a chdir
+        fs::canonicalize(&path)
             .map(|path| path.to_string_lossy().to_string())
-            .map_err(|_| anyhow!("Invalid directory"))
+            .map_err(|_| anyhow!("Invalid file: {}", path))
+    }
+
+    fn match_sni(sni: Option<String>) -> crate::Result<(String, String, String)> {
+        let sni = sni.ok_or(anyhow!("SNI value not found"))?;
+
+        // Expected format: <server name>,<cert path>,<key path>
+        match sni.split(',').collect::<Vec<&str>>()[..] {
+            [name, cert, key] => Ok((
+                name.to_ascii_lowercase(),
+                Self::match_file(Some(cert.to_string()))?,
+                Self::match_file(Some(key.to_string()))?,
+            )),
+            _ => Err(anyhow!("Invalid SNI value, expected <name>,<cert>,<key>")),
+        }
+    }
+}
+
+/// The contents of a config file, before validation. Unknown keys are rejected,
+/// so that typos do not silently fall back to defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    /// Where the file was read from, to name it in errors.
+    #[serde(skip)]
+    path: String,
+    /// Directory relative paths are resolved from.
+    #[serde(skip)]
+    base: PathBuf,
+    server: ServerSection,
+    tls: TlsSection,
+    timeouts: TimeoutsSection,
+    limits: LimitsSection,
+    rate_limit: RateLimitSection,
+    cors: CorsSection,
+    access_log: AccessLogSection,
+    logging: LoggingSection,
+    #[serde(rename = "routes")]
+    route_sections: Vec<RouteSection>,
+    #[serde(rename = "hosts")]
+    host_sections: Vec<HostSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    bind: Option<OneOrMany<IpAddr>>,
+    port: Option<u16>,
+    unix_socket: Option<String>,
+    unix_socket_mode: Option<u32>,
+    directory: Option<String>,
+    shutdown_timeout: Option<u64>,
+    pre_stop_delay: Option<u64>,
+    metrics_path: Option<String>,
+    health_routes: Option<bool>,
+    trusted_proxies: Vec<String>,
+    proxy_protocol: Option<bool>,
+}
+
+/// A value given alone or as a list, e.g. `bind = "::"` or `bind = ["0.0.0.0", "::"]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    port: Option<u16>,
+    cert: Option<String>,
+    key: Option<String>,
+    sni: Vec<SniSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SniSection {
+    name: String,
+    cert: String,
+    key: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutsSection {
+    idle: Option<u64>,
+    header: Option<u64>,
+    body: Option<u64>,
+    handler: Option<u64>,
+    write: Option<u64>,
+}
+
+impl TimeoutsSection {
+    fn apply(&self, timeouts: &mut Timeouts) {
+        let fields = [
+            (self.idle, &mut timeouts.idle),
+            (self.header, &mut timeouts.header_read),
+            (self.body, &mut timeouts.body_read),
+            (self.handler, &mut timeouts.handler),
+            (self.write, &mut timeouts.write),
+        ];
+
+        for (seconds, timeout) in fields {
+            if let Some(seconds) = seconds {
+                *timeout = Duration::from_secs(seconds);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_queued_connections: Option<usize>,
+    policy: Option<String>,
+    max_head_size: Option<usize>,
+    max_body_size: Option<usize>,
+    decode_request_bodies: Option<bool>,
+    max_decoded_body_size: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitSection {
+    limit: Option<usize>,
+    window: Option<u64>,
+    key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CorsSection {
+    origins: Vec<String>,
+    methods: Option<Vec<String>>,
+    headers: Option<Vec<String>>,
+    expose_headers: Option<Vec<String>>,
+    credentials: Option<bool>,
+    max_age: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AccessLogSection {
+    destination: Option<String>,
+    format: Option<String>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+    level: Option<String>,
+    json: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteSection {
+    path: String,
+    directory: Option<String>,
+    proxy: Option<Vec<String>>,
+    health_check: Option<String>,
+    health_interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostSection {
+    names: Vec<String>,
+    directory: Option<String>,
+    #[serde(default)]
+    routes: Vec<RouteSection>,
+}
+
+impl ConfigFile {
+    fn load(path: &str) -> crate::Result<ConfigFile> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read config file {}", path))?;
+
+        // The parser reports the line and key of the error
+        let mut file: ConfigFile = toml::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid config file {}: {}", path, e))?;
+
+        file.path = path.to_string();
+        file.base = Path::new(path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        Ok(file)
+    }
+
+    fn invalid(&self, key: &str) -> String {
+        format!("Invalid `{}` in {}", key, self.path)
+    }
+
+    fn resolve(&self, path: &str) -> String {
+        self.base.join(path).to_string_lossy().to_string()
+    }
+
+    fn dir(&self, key: &str, dir: &str) -> crate::Result<String> {
+        Config::match_dir(Some(self.resolve(dir))).with_context(|| self.invalid(key))
+    }
+
+    fn file(&self, key: &str, file: &str) -> crate::Result<String> {
+        Config::match_file(Some(self.resolve(file))).with_context(|| self.invalid(key))
+    }
+
+    fn apply_request_limits(&self, limits: &mut RequestLimits) {
+        if let Some(max) = self.limits.max_head_size {
+            limits.max_head_size = max;
+        }
+        if let Some(max) = self.limits.max_body_size {
+            limits.max_body_size = max;
+        }
+        if let Some(decode) = self.limits.decode_request_bodies {
+            limits.decode_bodies = decode;
+        }
+        if let Some(max) = self.limits.max_decoded_body_size {
+            limits.max_decoded_body_size = max;
+        }
+    }
+
+    fn apply_limits(&self, limits: &mut ConnectionLimits) -> crate::Result<()> {
+        // 0 lifts the limits, as on the command line
+        if let Some(max) = self.limits.max_connections {
+            limits.max_connections = Some(max).filter(|max| *max > 0);
+        }
+        if let Some(max) = self.limits.max_connections_per_ip {
+            limits.max_per_ip = Some(max).filter(|max| *max > 0);
+        }
+        if let Some(max) = self.limits.max_queued_connections {
+            limits.max_queued = Some(max).filter(|max| *max > 0);
+        }
+        if let Some(policy) = &self.limits.policy {
+            limits.policy = Config::match_policy(Some(policy.clone()))
+                .with_context(|| self.invalid("limits.policy"))?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_access_log(&self, access_log: &mut AccessLogConfig) -> crate::Result<()> {
+        if let Some(destination) = &self.access_log.destination {
+            access_log.destination = match Config::match_log_destination(Some(destination.clone()))?
+            {
+                LogDestination::File(path) => LogDestination::File(self.resolve(&path)),
+                destination => destination,
+            };
+        }
+        if let Some(format) = &self.access_log.format {
+            access_log.format = Config::match_log_format(Some(format.clone()))
+                .with_context(|| self.invalid("access_log.format"))?;
+        }
+        if let Some(max_size) = self.access_log.max_size {
+            access_log.max_size = max_size;
+        }
+        if let Some(max_files) = self.access_log.max_files {
+            access_log.max_files = max_files;
+        }
+
+        Ok(())
+    }
+
+    /// Validates the routes found under `table`, e.g. `routes` or `hosts[0].routes`.
+    fn routes(&self, table: &str, sections: &[RouteSection]) -> crate::Result<Vec<RouteConfig>> {
+        let mut routes = Vec::new();
+
+        for (index, route) in sections.iter().enumerate() {
+            let key = |field: &str| format!("{}[{}].{}", table, index, field);
+
+            if !route.path.starts_with('/') || route.path.contains([' ', ':', '*']) {
+                return Err(anyhow!("Expected a path like /assets"))
+                    .with_context(|| self.invalid(&key("path")));
+            }
+
+            let target = match (&route.directory, &route.proxy) {
+                (Some(dir), None) => RouteTarget::Directory(self.dir(&key("directory"), dir)?),
+                (None, Some(upstreams)) => RouteTarget::Proxy(self.proxy(key, route, upstreams)?),
+                (Some(_), Some(_)) => {
+                    return Err(anyhow!(
+                        "Expected either `{}` or `{}` in {}",
+                        key("directory"),
+                        key("proxy"),
+                        self.path
+                    ))
+                }
+                (None, None) => {
+                    return Err(anyhow!(
+                        "Missing `{}` or `{}` in {}",
+                        key("directory"),
+                        key("proxy"),
+                        self.path
+                    ))
+                }
+            };
+
+            routes.push(RouteConfig {
+                path: route.path.trim_end_matches('/').to_string(),
+                target,
+            });
+        }
+
+        Ok(routes)
+    }
+    fn proxy(
+        &self,
+        key: impl Fn(&str) -> String,
+        route: &RouteSection,
+        upstreams: &[String],
+    ) -> crate::Result<ProxyConfig> {
+        if upstreams.is_empty() {
+            return Err(anyhow!("Expected at least one upstream"))
+                .with_context(|| self.invalid(&key("proxy")));
+        }
+
+        let upstreams = upstreams
+            .iter()
+            .map(|url| proxy::parse_upstream(url))
+            .collect::<crate::Result<Vec<_>>>()
+            .with_context(|| self.invalid(&key("proxy")))?;
+
+        if let Some(path) = &route.health_check {
+            if !path.starts_with('/') || path.contains(' ') {
+                return Err(anyhow!("Expected a path like /healthz"))
+                    .with_context(|| self.invalid(&key("health_check")));
+            }
+        }
+
+        let health_interval = match route.health_interval {
+            Some(0) => {
+                return Err(anyhow!("Expected a number of seconds above 0"))
+                    .with_context(|| self.invalid(&key("health_interval")))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => Duration::from_secs(10),
+        };
+
+        Ok(ProxyConfig {
+            upstreams,
+            health_check: route.health_check.clone(),
+            health_interval,
+        })
+    }
+
+    fn hosts(&self) -> crate::Result<Vec<HostConfig>> {
+        let mut hosts = Vec::new();
+
+        for (index, host) in self.host_sections.iter().enumerate() {
+            let key = |field: &str| format!("hosts[{}].{}", index, field);
+
+            if host.names.is_empty() {
+                return Err(anyhow!("Expected at least one name"))
+                    .with_context(|| self.invalid(&key("names")));
+            }
+
+            let names = host
+                .names
+                .iter()
+                .map(|name| vhost::parse_name(name))
+                .collect::<crate::Result<Vec<_>>>()
+                .with_context(|| self.invalid(&key("names")))?;
+
+            let pub_dir = host
+                .directory
+                .as_ref()
+                .map(|dir| self.dir(&key("directory"), dir))
+                .transpose()?;
+
+            hosts.push(HostConfig {
+                names,
+                pub_dir,
+                routes: self.routes(&key("routes"), &host.routes)?,
+            });
+        }
+
+        Ok(hosts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> crate::Result<Command> {
+        let args = ["http-server-starter-rust"].iter().chain(args);
+
+        Command::parse(args.map(|arg| arg.to_string()))
+    }
+
+    fn config(args: &[&str]) -> Config {
+        match parse(args) {
+            Ok(Command::Run(config)) => *config,
+            Ok(_) => panic!("{:?} printed the help or version", args),
+            Err(e) => panic!("{:?} failed: {:#}", args, e),
+        }
+    }
+
+    /// Whether the arguments fail as invalid, for the process to exit with 2.
+    fn is_arg_error(args: &[&str]) -> bool {
+        parse(args).is_err_and(|e| e.is::<ArgError>())
+    }
+
+    #[test]
+    fn prints_the_help_whatever_the_other_flags() {
+        assert!(matches!(parse(&["-h"]), Ok(Command::PrintHelp)));
+        assert!(matches!(
+            parse(&["--port", "80", "--help"]),
+            Ok(Command::PrintHelp)
+        ));
+        assert!(matches!(
+            parse(&["-h", "--unknown"]),
+            Ok(Command::PrintHelp)
+        ));
+        assert!(matches!(parse(&["-V", "-h"]), Ok(Command::PrintHelp)));
+        assert!(matches!(parse(&["--version"]), Ok(Command::PrintVersion)));
+    }
+
+    #[test]
+    fn only_takes_help_in_flag_position() {
+        // The value of an option, not a flag
+        assert!(is_arg_error(&["--directory", "-h"]));
+        assert!(is_arg_error(&["--access-log-format", "-h"]));
+        assert!(is_arg_error(&["--access-log-format=-h"]));
+    }
+
+    #[test]
+    fn splits_values_given_with_an_equals_sign() {
+        assert_eq!(config(&["--port=8080"]).port, 8080);
+        assert_eq!(config(&["-p", "8081"]).port, 8081);
+        assert_eq!(
+            config(&["--header-timeout=5"]).timeouts.header_read,
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments_as_such() {
+        assert!(is_arg_error(&["--unknown"]));
+        assert!(is_arg_error(&["serve"]));
+        assert!(is_arg_error(&["--port"]));
+        assert!(is_arg_error(&["--port", "http"]));
+        assert!(is_arg_error(&["--port", "65536"]));
+        assert!(is_arg_error(&["--config"]));
+        assert!(is_arg_error(&["--bind", "localhost"]));
+        assert!(is_arg_error(&["--trusted-proxy", "10.0.0.0/40"]));
+        assert!(is_arg_error(&["--connection-limit-policy", "drop"]));
+        assert!(is_arg_error(&["--rate-limit-key", "cookie"]));
+        assert!(is_arg_error(&["--proxy-protocol", "maybe"]));
+    }
+
+    #[test]
+    fn fails_on_missing_config_files_without_blaming_the_arguments() {
+        let result = parse(&["--config", "/nonexistent/server.toml"]);
+
+        assert!(result.as_ref().is_err_and(|e| !e.is::<ArgError>()));
+    }
+
+    #[test]
+    fn lets_flags_override_the_config_file() {
+        let path = env::temp_dir().join(format!("config-test-{}.toml", std::process::id()));
+        fs::write(&path, "[server]\nport = 8080\n\n[timeouts]\nidle = 5\n").unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let from_file = config(&["--config", &path]);
+        let overridden = config(&["--port", "9090", "--config", &path]);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(from_file.port, 8080);
+        assert_eq!(from_file.timeouts.idle, Duration::from_secs(5));
+        assert_eq!(overridden.port, 9090);
+        assert_eq!(overridden.timeouts.idle, Duration::from_secs(5));
     }
 }
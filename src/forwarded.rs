@@ -0,0 +1,432 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::request::Request;
+
+/// A network of addresses, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of dual-stack sockets show up as IPv4-mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    /// Parses a network, a single address standing for a network of its own.
+    fn from_str(cidr: &str) -> Result<Cidr> {
+        let invalid = || anyhow!("Invalid network {}, expected e.g. 10.0.0.0/8", cidr);
+
+        let (network, prefix_len) = match cidr.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (cidr, None),
+        };
+
+        let network = network
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(invalid)?,
+            None => max_len,
+        };
+
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// The client a request originates from, as told by trusted proxies in between.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub ip: IpAddr,
+    /// `http` or `https`.
+    pub scheme: &'static str,
+    /// The `Host` the client requested.
+    pub host: Option<String>,
+}
+
+impl Client {
+    /// The peer itself as the client, for requests whose headers are unknown.
+    pub fn peer(peer_addr: SocketAddr, tls: bool) -> Client {
+        Client {
+            ip: peer_addr.ip().to_canonical(),
+            scheme: if tls { "https" } else { "http" },
+            host: None,
+        }
+    }
+}
+
+/// Proxies whose `Forwarded` and `X-Forwarded-*` headers are believed. Those of other
+/// peers are ignored, as any client can send them.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<Cidr>) -> TrustedProxies {
+        TrustedProxies { networks }
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// Finds the client of a request received from `peer_addr`.
+    ///
+    /// Proxies append the address they received the request from, so the addresses are
+    /// walked from the last one, through trusted proxies, to the first untrusted one.
+    /// `Forwarded` is preferred over the `X-Forwarded-*` headers when both are present.
+    pub fn resolve(&self, request: &Request, peer_addr: SocketAddr, tls: bool) -> Client {
+        let headers = request.headers();
+
+        let mut client = Client {
+            host: headers.get("Host").cloned(),
+            ..Client::peer(peer_addr, tls)
+        };
+
+        if !self.is_trusted(client.ip) {
+            return client;
+        }
+
+        let hops = match headers.get("Forwarded") {
+            Some(forwarded) => parse_forwarded(forwarded),
+            None => parse_x_forwarded(request),
+        };
+
+        for hop in hops.iter().rev() {
+            let Some(ip) = hop.ip else {
+                // Obfuscated or unknown addresses cannot be walked past
+                break;
+            };
+
+            client.ip = ip;
+
+            if let Some(scheme) = hop.scheme {
+                client.scheme = scheme;
+            }
+            if let Some(host) = &hop.host {
+                client.host = Some(host.clone());
+            }
+
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// One proxy's account of where it received the request from.
+#[derive(Debug, Default)]
+struct Hop {
+    ip: Option<IpAddr>,
+    scheme: Option<&'static str>,
+    host: Option<String>,
+}
+
+/// Parses `Forwarded: for=192.0.2.60;proto=https;host=example.com, for="[2001:db8::1]"`
+/// (RFC 7239).
+fn parse_forwarded(forwarded: &str) -> Vec<Hop> {
+    forwarded
+        .split(',')
+        .map(|element| {
+            let mut hop = Hop::default();
+
+            for pair in element.split(';') {
+                let Some((key, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value = value.trim().trim_matches('"');
+
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.ip = parse_node(value),
+                    "proto" => hop.scheme = parse_scheme(value),
+                    "host" => hop.host = parse_host(value),
+                    _ => {}
+                }
+            }
+
+            hop
+        })
+        .collect()
+}
+
+/// Builds hops from `X-Forwarded-For`, one per address. `X-Forwarded-Proto` and
+/// `X-Forwarded-Host` are not lists of hops, but describe the request of the client as the
+/// proxy facing it received it, so are taken as soon as the peer is trusted.
+fn parse_x_forwarded(request: &Request) -> Vec<Hop> {
+    let headers = request.headers();
+
+    let Some(forwarded_for) = headers.get("X-Forwarded-For") else {
+        return Vec::new();
+    };
+
+    let mut hops = forwarded_for
+        .split(',')
+        .map(|node| Hop {
+            ip: parse_node(node.trim()),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    let first_value = |header: &str| {
+        headers
+            .get(header)
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+    };
+
+    if let Some(last) = hops.last_mut() {
+        last.scheme = first_value("X-Forwarded-Proto").and_then(parse_scheme);
+        last.host = first_value("X-Forwarded-Host").and_then(parse_host);
+    }
+
+    hops
+}
+
+/// Parses an address like `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::1` or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|ip| ip.parse().ok())
+        })
+        .map(|ip: IpAddr| ip.to_canonical())
+}
+
+fn parse_scheme(scheme: &str) -> Option<&'static str> {
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" => Some("http"),
+        "https" => Some("https"),
+        _ => None,
+    }
+}
+
+fn parse_host(host: &str) -> Option<String> {
+    let valid = !host.is_empty()
+        && !host.contains(|c: char| c.is_whitespace() || c.is_control() || c == '/');
+
+    valid.then(|| host.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn cidr(cidr: &str) -> Cidr {
+        cidr.parse().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn proxies(networks: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(networks.iter().map(|network| cidr(network)).collect())
+    }
+
+    fn request(headers: &[&str]) -> Request {
+        let head = ["GET / HTTP/1.1"]
+            .iter()
+            .chain(headers)
+            .map(|line| format!("{}\r\n", line))
+            .collect::<String>();
+
+        Request::parse_head(&head).unwrap()
+    }
+
+    fn resolve(proxies: &TrustedProxies, peer: &str, headers: &[&str]) -> (String, &'static str) {
+        let client = proxies.resolve(&request(headers), peer.parse().unwrap(), false);
+
+        (client.ip.to_string(), client.scheme)
+    }
+
+    #[test]
+    fn parses_networks_and_single_addresses() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("192.0.2.1").to_string(), "192.0.2.1/32");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+    }
+
+    #[test]
+    fn takes_ipv4_mapped_networks_as_ipv4() {
+        assert_eq!(cidr("::ffff:10.0.0.0/8").to_string(), "10.0.0.0/8");
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for invalid in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "example.com",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn matches_addresses_within_the_prefix() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("fd00::/8").contains(ip("fd12::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe80::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_against_ipv4_networks() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+    }
+
+    #[test]
+    fn ignores_headers_of_untrusted_peers() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(
+            resolve(
+                &proxies,
+                "203.0.113.7:4000",
+                &["X-Forwarded-For: 198.51.100.1", "X-Forwarded-Proto: https"]
+            ),
+            ("203.0.113.7".to_string(), "http")
+        );
+    }
+
+    #[test]
+    fn walks_back_to_the_first_untrusted_address() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(
+            resolve(
+                &proxies,
+                "10.0.0.1:4000",
+                &[
+                    "X-Forwarded-For: 192.0.2.1, 198.51.100.1, 10.0.0.2",
+                    "X-Forwarded-Proto: https",
+                ]
+            ),
+            ("198.51.100.1".to_string(), "https")
+        );
+    }
+
+    #[test]
+    fn trusts_ipv4_mapped_peers() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(
+            resolve(
+                &proxies,
+                "[::ffff:10.0.0.1]:4000",
+                &["X-Forwarded-For: 198.51.100.1"]
+            ),
+            ("198.51.100.1".to_string(), "http")
+        );
+        assert_eq!(
+            resolve(&proxies, "[::ffff:192.0.2.1]:4000", &[]),
+            ("192.0.2.1".to_string(), "http")
+        );
+    }
+
+    #[test]
+    fn prefers_forwarded_over_x_forwarded_headers() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(
+            resolve(
+                &proxies,
+                "10.0.0.1:4000",
+                &[
+                    "Forwarded: for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+                    "X-Forwarded-For: 198.51.100.1",
+                ]
+            ),
+            ("2001:db8::1".to_string(), "https")
+        );
+    }
+
+    #[test]
+    fn stops_at_obfuscated_addresses() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(
+            resolve(
+                &proxies,
+                "10.0.0.1:4000",
+                &["Forwarded: for=198.51.100.1, for=_hidden, for=10.0.0.2"]
+            ),
+            ("10.0.0.2".to_string(), "http")
+        );
+    }
+
+    #[test]
+    fn takes_the_host_from_trusted_proxies() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let request = request(&[
+            "Host: internal:8080",
+            "X-Forwarded-For: 198.51.100.1",
+            "X-Forwarded-Host: example.com",
+        ]);
+
+        let client = proxies.resolve(&request, "10.0.0.1:4000".parse().unwrap(), false);
+        assert_eq!(client.host.as_deref(), Some("example.com"));
+
+        let client = proxies.resolve(&request, "192.0.2.1:4000".parse().unwrap(), true);
+        assert_eq!(client.host.as_deref(), Some("internal:8080"));
+        assert_eq!(client.scheme, "https");
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{runtime::Handle, task::JoinSet, time::timeout};
+use tracing::warn;
+
+use crate::{response::ResponseBuilder, server::RequestInfo, shutdown::Shutdown};
+
+/// Path of the liveness probe.
+pub const HEALTHZ_PATH: &str = "/healthz";
+
+/// Path of the readiness probe.
+pub const READYZ_PATH: &str = "/readyz";
+
+/// How long a readiness check may take before it is considered failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+type CheckFn = dyn Fn() -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync;
+
+/// Liveness and readiness of the server, served on `/healthz` and `/readyz`.
+///
+/// The server is ready when every registered check passes and it is not stopping, so that
+/// load balancers stop sending it traffic during the pre-stop delay, before connections
+/// are refused.
+pub struct Health {
+    checks: RwLock<Vec<(String, Arc<CheckFn>)>>,
+    shutdown: Shutdown,
+}
+
+impl std::fmt::Debug for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let checks = self.checks.read().unwrap();
+
+        f.debug_struct("Health")
+            .field(
+                "checks",
+                &checks.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Health {
+    pub fn new(shutdown: Shutdown) -> Health {
+        Health {
+            checks: RwLock::new(Vec::new()),
+            shutdown,
+        }
+    }
+
+    /// Registers a check run on every readiness probe. A check failing, or taking longer
+    /// than 5 seconds, makes the server not ready.
+    pub fn add_check<F, Fut>(&self, name: &str, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let check: Arc<CheckFn> = Arc::new(move || Box::pin(check()));
+
+        self.checks.write().unwrap().push((name.to_string(), check));
+    }
+
+    /// Runs every check concurrently, returning each check name with its result,
+    /// in registration order.
+    pub async fn run_checks(&self) -> Vec<(String, Result<()>)> {
+        let checks = self.checks.read().unwrap().clone();
+        let mut running = JoinSet::new();
+
+        for (index, (name, check)) in checks.into_iter().enumerate() {
+            running.spawn(async move {
+                let result = match timeout(CHECK_TIMEOUT, check()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(anyhow!("timed out")),
+                };
+
+                (index, name, result)
+            });
+        }
+
+        let mut results = Vec::new();
+
+        while let Some(joined) = running.join_next().await {
+            match joined {
+                Ok(result) => results.push(result),
+                Err(e) => results.push((usize::MAX, "unknown".to_string(), Err(e.into()))),
+            }
+        }
+
+        results.sort_by_key(|(index, _, _)| *index);
+
+        results
+            .into_iter()
+            .map(|(_, name, result)| (name, result))
+            .collect()
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.shutdown.is_stopping()
+    }
+}
+
+/// Numbers the probe files of `dir_writable`, for concurrent checks not to share one.
+static PROBE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Checks that a directory exists and files can be created in it.
+pub async fn dir_writable(dir: PathBuf) -> Result<()> {
+    let probe = dir.join(format!(
+        ".readyz-{}-{}",
+        std::process::id(),
+        PROBE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    tokio::fs::write(&probe, b"")
+        .await
+        .map_err(|e| anyhow!("{} is not writable: {}", dir.display(), e))?;
+    tokio::fs::remove_file(&probe).await?;
+
+    Ok(())
+}
+
+/// Liveness probe: answering at all means the server is alive.
+pub fn handle_healthz(_: RequestInfo) -> Result<ResponseBuilder> {
+    Ok(ResponseBuilder::new()
+        .status(200, "OK")
+        .header("Content-Type", "text/plain")
+        .header("Cache-Control", "no-store")
+        .body(b"ok\n"))
+}
+
+/// Readiness probe, listing the result of every check.
+pub fn handle_readyz(req_info: RequestInfo) -> Result<ResponseBuilder> {
+    let health = req_info.server_info().health();
+
+    // Handlers run on the blocking thread pool, where waiting on the runtime is allowed
+    let results = Handle::current().block_on(health.run_checks());
+
+    let mut ready = true;
+    let mut body = String::new();
+
+    for (name, result) in &results {
+        match result {
+            Ok(_) => body.push_str(&format!("[+] {} ok\n", name)),
+            Err(e) => {
+                // The details, like paths and OS errors, are for the logs only, as the probe
+                // may be reachable by anyone
+                warn!(check = %name, "Readiness check failed: {:#}", e);
+
+                ready = false;
+                body.push_str(&format!("[-] {} failed\n", name));
+            }
+        }
+    }
+
+    if health.is_stopping() {
+        ready = false;
+        body.push_str("[-] stopping\n");
+    }
+
+    let response = if ready {
+        body.push_str("ready\n");
+        ResponseBuilder::new().status(200, "OK")
+    } else {
+        body.push_str("not ready\n");
+        ResponseBuilder::new().status(503, "Service Unavailable")
+    };
+
+    Ok(response
+        .header("Content-Type", "text/plain")
+        .header("Cache-Control", "no-store")
+        .body(body.as_bytes()))
+}
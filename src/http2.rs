@@ -0,0 +1,507 @@
+use std::{future::poll_fn, net::SocketAddr};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use h2::{
+    server::{self, SendResponse},
+    RecvStream, SendStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    task::JoinSet,
+    time::{sleep, timeout, Duration},
+};
+use tracing::debug;
+
+use crate::{
+    access_log::RequestLog,
+    request::{HTTPMethod, Headers, Request, RequestLine},
+    request_id,
+    response::{Response, ResponseBuilder},
+    server::{error_response, Router},
+    shutdown::Shutdown,
+    utils::Rewind,
+};
+
+/// ALPN protocol identifier of HTTP/2 over TLS.
+pub const ALPN_PROTOCOL: &[u8] = b"h2";
+
+/// The client connection preface that starts every HTTP/2 connection.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The part of the preface an HTTP/1.1 request head reader stops at.
+pub const PREFACE_HEAD: &[u8] = b"PRI * HTTP/2.0\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+const SETTING_LEN: usize = 6;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const DEFAULT_WINDOW_SIZE: usize = 65_535;
+
+const FRAME_TYPE_DATA: u8 = 0x0;
+const FRAME_TYPE_HEADERS: u8 = 0x1;
+const FRAME_TYPE_SETTINGS: u8 = 0x4;
+const FRAME_TYPE_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+/// Headers that HTTP/2 either forbids, as they are connection-specific,
+/// or carries differently (`Host` becomes the `:authority` pseudo-header).
+const EXCLUDED_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Http2-Settings",
+    "Host",
+    "Content-Length",
+];
+
+/// Serves an HTTP/2 connection, dispatching every stream to the router concurrently.
+///
+/// When the server shuts down, or no stream was open for the idle timeout, a GOAWAY frame
+/// stops the client from opening new streams, and the connection closes once the open ones
+/// complete. Streams taking longer than the idle timeout, like event streams, keep the
+/// connection open.
+pub async fn serve<S>(
+    io: S,
+    peer_addr: SocketAddr,
+    router: Router,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = server::handshake(io).await?;
+    let mut shutdown_started = shutdown.clone();
+    let mut going_away = false;
+    let idle = router.timeouts().idle;
+    let mut streams = JoinSet::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => accepted,
+            // The idle timer starts over once a stream completes
+            Some(_) = streams.join_next(), if !streams.is_empty() => continue,
+            _ = shutdown_started.wait(), if !going_away => {
+                connection.graceful_shutdown();
+                going_away = true;
+                continue;
+            }
+            _ = sleep(idle), if !going_away && streams.is_empty() => {
+                connection.graceful_shutdown();
+                going_away = true;
+                continue;
+            }
+        };
+
+        let Some(result) = accepted else {
+            break;
+        };
+
+        let (request, respond) = result?;
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+
+        streams.spawn(async move {
+            if let Err(e) = serve_stream(request, respond, peer_addr, router, shutdown).await {
+                debug!(%peer_addr, "HTTP/2 stream error: {:#}", e);
+            }
+        });
+    }
+
+    // Streams still running finish on their own, as dropping the set would abort them
+    streams.detach_all();
+
+    Ok(())
+}
+
+/// Checks whether an HTTP/1.1 request asks to switch the connection to cleartext HTTP/2.
+pub fn is_upgrade(request: &Request) -> bool {
+    // The request is replayed as the first HTTP/2 stream,
+    // so its body must fit in the initial flow-control window
+    request.request_line().version() == "HTTP/1.1"
+        && request.has_header_token("Upgrade", "h2c")
+        && request.has_header_token("Connection", "Upgrade")
+        && upgrade_settings(request).is_some()
+        && request.body().map_or(0, |body| body.len()) <= DEFAULT_WINDOW_SIZE
+}
+
+/// The settings of an upgrade request, carried base64url-encoded in `HTTP2-Settings` as the
+/// payload of a SETTINGS frame. `None` if they are invalid, in which case the connection
+/// stays on HTTP/1.1.
+fn upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let encoded = request.headers().get("Http2-Settings")?;
+    let settings = URL_SAFE_NO_PAD
+        .decode(encoded.trim().trim_end_matches('='))
+        .ok()?;
+
+    (settings.len() % SETTING_LEN == 0 && settings.len() <= DEFAULT_MAX_FRAME_SIZE / 2)
+        .then_some(settings)
+}
+
+/// Switches an HTTP/1.1 connection to HTTP/2 after an `Upgrade: h2c` request,
+/// and answers that request on stream 1 as required by RFC 7540 section 3.2.
+pub async fn upgrade<S>(
+    mut stream: S,
+    request: Request,
+    peer_addr: SocketAddr,
+    router: Router,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let switching = ResponseBuilder::new()
+        .status(101, "Switching Protocols")
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "h2c")
+        .build()?;
+    stream.write_all(&switching.as_bytes()).await?;
+
+    // The client now sends the connection preface, followed by its SETTINGS frame
+    let header_read = router.timeouts().header_read;
+
+    let mut prefix = vec![0; PREFACE.len() + FRAME_HEADER_LEN];
+    timeout(header_read, stream.read_exact(&mut prefix))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the HTTP/2 connection preface"))??;
+
+    let settings_header = prefix[PREFACE.len()..].to_vec();
+    if !prefix.starts_with(PREFACE) || settings_header[3] != FRAME_TYPE_SETTINGS {
+        return Err(anyhow!("Invalid HTTP/2 connection preface after upgrade"));
+    }
+
+    let settings_len = u32::from_be_bytes([
+        0,
+        settings_header[0],
+        settings_header[1],
+        settings_header[2],
+    ]) as usize;
+    let mut settings = vec![0; settings_len];
+    timeout(header_read, stream.read_exact(&mut settings))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the HTTP/2 connection preface"))??;
+
+    // The settings of the upgrade request apply first, as if the client had sent them at the
+    // start of its SETTINGS frame. Merging them into that frame keeps to the acknowledgement
+    // the client expects
+    let mut merged = upgrade_settings(&request).unwrap_or_default();
+    merged.extend_from_slice(&settings);
+
+    if merged.len() > DEFAULT_MAX_FRAME_SIZE {
+        return Err(anyhow!("HTTP/2 settings too large"));
+    }
+
+    prefix.truncate(PREFACE.len());
+    prefix.extend_from_slice(&(merged.len() as u32).to_be_bytes()[1..]);
+    prefix.extend_from_slice(&settings_header[3..]);
+    prefix.extend_from_slice(&merged);
+
+    // Replay the upgraded request as stream 1, right after the client's settings
+    prefix.extend_from_slice(&encode_request_frames(&request));
+
+    serve(Rewind::new(prefix, stream), peer_addr, router, shutdown).await
+}
+
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer_addr: SocketAddr,
+    router: Router,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let timeouts = router.timeouts();
+    let max_body_size = router.request_limits().max_body_size;
+
+    let read = timeout(timeouts.body_read, read_request(request, max_body_size));
+
+    let (response, request_log) = match read.await.map(|request| request.transpose()) {
+        Ok(Some(request)) => {
+            let mut request = request?;
+            request_id::assign(&mut request);
+
+            let client = router.resolve_client(&request, peer_addr);
+            let mut request_log = RequestLog::new(
+                client.clone(),
+                request.request_id().unwrap_or_default(),
+                Some(&request),
+            );
+
+            let (response, route) = router.respond(request, peer_addr, client).await;
+            request_log.set_route(route);
+
+            (response, request_log)
+        }
+        Ok(None) => {
+            let request_id = request_id::generate();
+
+            (
+                error_response(413, "Payload Too Large", &request_id),
+                RequestLog::new(router.peer_client(peer_addr), &request_id, None),
+            )
+        }
+        Err(_) => {
+            let request_id = request_id::generate();
+
+            (
+                error_response(408, "Request Timeout", &request_id),
+                RequestLog::new(router.peer_client(peer_addr), &request_id, None),
+            )
+        }
+    };
+
+    let mut response = response.build()?;
+    let body = Bytes::copy_from_slice(response.body());
+
+    let head = convert_response(&response)?;
+
+    let body_stream = response.take_body_stream();
+    let end_of_stream = body.is_empty() && body_stream.is_none();
+
+    let mut send = respond.send_response(head, end_of_stream)?;
+    let mut bytes = body.len();
+
+    if let Some(mut body_stream) = body_stream {
+        // Streams are ended early when the server shuts down, as they may never end otherwise
+        loop {
+            let chunk = tokio::select! {
+                chunk = body_stream.recv() => chunk,
+                _ = shutdown.wait() => None,
+            };
+
+            let Some(chunk) = chunk else {
+                break;
+            };
+
+            bytes += chunk.len();
+            send_body(&mut send, Bytes::from(chunk), false, timeouts.write).await?;
+        }
+
+        send.send_data(Bytes::new(), true)?;
+    } else if !body.is_empty() {
+        send_body(&mut send, body, true, timeouts.write).await?;
+    }
+
+    router.complete(&request_log, response.status_code(), bytes);
+
+    Ok(())
+}
+
+/// Reads a request and its body, or returns `None` if the body is larger than
+/// `max_body_size`, as announced or once read.
+async fn read_request(
+    request: http::Request<RecvStream>,
+    max_body_size: usize,
+) -> Result<Option<Request>> {
+    let (parts, mut recv) = request.into_parts();
+
+    let method = HTTPMethod::parse_method(parts.method.as_str())
+        .map_err(|_| anyhow!("Unsupported method {}", parts.method))?;
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    let mut headers = Headers::new();
+
+    for (name, value) in &parts.headers {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+
+        // Repeated fields are combined, as a single HTTP/1.1 header line would carry them.
+        // Cookies are split into several fields, to be joined with "; " (RFC 7540 section
+        // 8.1.2.5)
+        let value = match headers.get(name.as_str()) {
+            Some(existing) if name == http::header::COOKIE => format!("{}; {}", existing, value),
+            Some(existing) => format!("{}, {}", existing, value),
+            None => value,
+        };
+
+        headers.insert(name.to_string(), value);
+    }
+
+    // Handlers rely on the Host header, which HTTP/2 carries in the :authority pseudo-header
+    if let Some(authority) = parts.uri.authority() {
+        if !headers.contains_key("Host") {
+            headers.insert("host".to_string(), authority.to_string());
+        }
+    }
+
+    let content_length = headers
+        .get("Content-Length")
+        .and_then(|length| length.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > max_body_size) {
+        return Ok(None);
+    }
+
+    let mut body = Vec::new();
+
+    while let Some(chunk) = recv.data().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > max_body_size {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+        recv.flow_control().release_capacity(chunk.len())?;
+    }
+
+    if !headers.contains_key("Content-Length") {
+        headers.insert("content-length".to_string(), body.len().to_string());
+    }
+
+    let request_line = RequestLine::new(method, path, "HTTP/2.0");
+
+    Ok(Some(Request::new(request_line, headers, body)))
+}
+
+fn convert_response(response: &Response) -> Result<http::Response<()>> {
+    let mut builder = http::Response::builder().status(response.status_code());
+
+    for (key, value) in response.header_lines() {
+        if EXCLUDED_HEADERS
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(key))
+        {
+            continue;
+        }
+
+        builder = builder.header(key, value);
+    }
+
+    match response.headers().get("Content-Length") {
+        Some(length) => builder = builder.header("content-length", length.as_str()),
+        None if !response.is_streaming() => {
+            builder = builder.header("content-length", response.body().len())
+        }
+        None => {}
+    }
+
+    Ok(builder.body(())?)
+}
+
+/// Sends (part of) a response body, waiting for the peer to grant flow-control capacity
+/// as needed, for up to the write timeout each time.
+async fn send_body(
+    send: &mut SendStream<Bytes>,
+    mut body: Bytes,
+    end_of_stream: bool,
+    write_timeout: Duration,
+) -> Result<()> {
+    while !body.is_empty() {
+        send.reserve_capacity(body.len());
+
+        let capacity = timeout(write_timeout, poll_fn(|cx| send.poll_capacity(cx)))
+            .await
+            .map_err(|_| anyhow!("Write timed out"))?
+            .ok_or(anyhow!("Stream closed before the body was sent"))??;
+
+        let chunk = body.split_to(capacity.min(body.len()));
+        send.send_data(chunk, body.is_empty() && end_of_stream)?;
+    }
+
+    Ok(())
+}
+
+/// Encodes an HTTP/1.1 request as the HEADERS and DATA frames of HTTP/2 stream 1.
+fn encode_request_frames(request: &Request) -> Vec<u8> {
+    let host = request.headers().get("Host").map(|x| x.as_str());
+
+    let mut block = Vec::new();
+    encode_header(&mut block, ":method", &format!("{:?}", request.method()));
+    encode_header(&mut block, ":scheme", "http");
+    encode_header(&mut block, ":path", request.request_line().path());
+    if let Some(host) = host {
+        encode_header(&mut block, ":authority", host);
+    }
+
+    for (key, value) in request.headers() {
+        if EXCLUDED_HEADERS
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(key))
+        {
+            continue;
+        }
+
+        encode_header(&mut block, &key.to_ascii_lowercase(), value);
+    }
+
+    let body = request
+        .body()
+        .map(|body| body.as_slice())
+        .unwrap_or_default();
+
+    let mut frames = Vec::new();
+
+    // The header block is split into a HEADERS frame and as many CONTINUATION frames as needed
+    let mut chunks = block.chunks(DEFAULT_MAX_FRAME_SIZE).peekable();
+    let mut frame_type = FRAME_TYPE_HEADERS;
+
+    while let Some(chunk) = chunks.next() {
+        let mut flags = 0;
+        if chunks.peek().is_none() {
+            flags |= FLAG_END_HEADERS;
+        }
+        if frame_type == FRAME_TYPE_HEADERS && body.is_empty() {
+            flags |= FLAG_END_STREAM;
+        }
+
+        encode_frame(&mut frames, frame_type, flags, chunk);
+        frame_type = FRAME_TYPE_CONTINUATION;
+    }
+
+    let mut chunks = body.chunks(DEFAULT_MAX_FRAME_SIZE).peekable();
+
+    while let Some(chunk) = chunks.next() {
+        let flags = if chunks.peek().is_none() {
+            FLAG_END_STREAM
+        } else {
+            0
+        };
+
+        encode_frame(&mut frames, FRAME_TYPE_DATA, flags, chunk);
+    }
+
+    frames
+}
+
+fn encode_frame(frames: &mut Vec<u8>, frame_type: u8, flags: u8, payload: &[u8]) {
+    // Stream 1 is the stream of the upgraded request
+    let stream_id: u32 = 1;
+
+    frames.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frames.push(frame_type);
+    frames.push(flags);
+    frames.extend_from_slice(&stream_id.to_be_bytes());
+    frames.extend_from_slice(payload);
+}
+
+/// Encodes a header as an HPACK literal without indexing, so the decoder's
+/// dynamic table is left untouched for the client's own header blocks.
+fn encode_header(block: &mut Vec<u8>, name: &str, value: &str) {
+    block.push(0x00);
+    encode_string(block, name.as_bytes());
+    encode_string(block, value.as_bytes());
+}
+
+fn encode_string(block: &mut Vec<u8>, string: &[u8]) {
+    // Length as an HPACK integer with a 7-bit prefix, no Huffman coding
+    let mut len = string.len();
+
+    if len < 0x7f {
+        block.push(len as u8);
+    } else {
+        block.push(0x7f);
+        len -= 0x7f;
+
+        while len >= 0x80 {
+            block.push((len % 0x80) as u8 | 0x80);
+            len /= 0x80;
+        }
+        block.push(len as u8);
+    }
+
+    block.extend_from_slice(string);
+}
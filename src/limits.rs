@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{ConnectionLimits, LimitPolicy};
+
+/// How long clients turned away at the connection limit are asked to wait before retrying.
+pub const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Connection counters, updated as connections are accepted and closed.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ConnectionStats {
+    /// Connections currently being served.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Accepted connections waiting for a free slot, under the queue policy.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Connections accepted since the server started, including rejected ones.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed right away because a limit was reached.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// Enforces the maximum number of concurrent connections, overall and per client IP.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+    max_per_ip: Option<usize>,
+    max_queued: Option<usize>,
+    policy: LimitPolicy,
+    connections_by_ip: Mutex<HashMap<IpAddr, usize>>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> ConnectionLimiter {
+        ConnectionLimiter {
+            semaphore: limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            max_per_ip: limits.max_per_ip,
+            max_queued: limits.max_queued,
+            policy: limits.policy,
+            connections_by_ip: Mutex::new(HashMap::new()),
+            stats: Arc::new(ConnectionStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<ConnectionStats> {
+        self.stats.clone()
+    }
+
+    /// Under the stop-accepting policy, waits for a free slot before the next connection
+    /// is accepted, leaving pending connections in the listen backlog meanwhile.
+    pub async fn reserve(&self) -> Option<OwnedSemaphorePermit> {
+        match (&self.semaphore, self.policy) {
+            (Some(semaphore), LimitPolicy::StopAccepting) => {
+                semaphore.clone().acquire_owned().await.ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Admits an accepted connection, returning a guard that frees its slot when dropped.
+    /// `None` means the connection must be rejected: the client IP is over its own cap,
+    /// the server is full under the reject policy, or the queue is full under the queue one.
+    ///
+    /// Clients without an IP, like those of Unix sockets, are only subject to the overall cap.
+    pub async fn admit(
+        self: &Arc<Self>,
+        ip: Option<IpAddr>,
+        reserved: Option<OwnedSemaphorePermit>,
+    ) -> Option<ConnectionGuard> {
+        self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+
+        // Queued connections count towards the per-IP cap, so one client cannot fill the queue
+        if ip.is_some_and(|ip| !self.add_ip(ip)) {
+            self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        // Dropping the guard undoes `add_ip`, even when no slot is obtained
+        let mut guard = ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+            _permit: None,
+            queued: false,
+            active: false,
+        };
+
+        let permit = match (&self.semaphore, reserved) {
+            (_, Some(permit)) => Some(permit),
+            (None, None) => None,
+            (Some(semaphore), None) => match self.policy {
+                LimitPolicy::Queue => {
+                    let queued = self.stats.queued.fetch_add(1, Ordering::Relaxed);
+
+                    if self.max_queued.is_some_and(|max| queued >= max) {
+                        self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                        self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                        return None;
+                    }
+
+                    // The guard leaves the queue when dropped, should the wait be cancelled
+                    guard.queued = true;
+
+                    let permit = semaphore.clone().acquire_owned().await.ok();
+
+                    guard.queued = false;
+                    self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+
+                    permit
+                }
+                LimitPolicy::Reject | LimitPolicy::StopAccepting => {
+                    match semaphore.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                            return None;
+                        }
+                    }
+                }
+            },
+        };
+
+        guard._permit = permit;
+        guard.active = true;
+        self.stats.active.fetch_add(1, Ordering::Relaxed);
+
+        Some(guard)
+    }
+
+    fn add_ip(&self, ip: IpAddr) -> bool {
+        let mut connections_by_ip = self.connections_by_ip.lock().unwrap();
+        let count = connections_by_ip.entry(ip).or_default();
+
+        if self.max_per_ip.is_some_and(|max| *count >= max) {
+            if *count == 0 {
+                connections_by_ip.remove(&ip);
+            }
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+
+    fn remove_ip(&self, ip: IpAddr) {
+        let mut connections_by_ip = self.connections_by_ip.lock().unwrap();
+
+        if let Some(count) = connections_by_ip.get_mut(&ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                connections_by_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Holds the slot of an admitted connection until the connection closes.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: Option<IpAddr>,
+    _permit: Option<OwnedSemaphorePermit>,
+    queued: bool,
+    active: bool,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.queued {
+            self.limiter.stats.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        if self.active {
+            self.limiter.stats.active.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        if let Some(ip) = self.ip {
+            self.limiter.remove_ip(ip);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    env, fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::fs::FileTypeExt,
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use anyhow::{anyhow, Context, Result};
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Peer address given to clients of Unix sockets, which have none. The unspecified address
+/// cannot be that of a TCP client, so that Unix clients are not mistaken for loopback ones.
+pub const UNIX_PEER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// First file descriptor passed with `LISTEN_FDS`, after stdin, stdout and stderr.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// Set instead of `LISTEN_PID` by a server handing its sockets over to a new process,
+/// which cannot know the PID of the process before starting it.
+pub const PARENT_PID_VAR: &str = "LISTEN_PARENT_PID";
+
+/// The PID of the previous process of this server that handed the sockets over, or 0.
+static PARENT_PID: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the sockets were handed over to a new process, which then owns the socket files.
+static HANDED_OFF: AtomicBool = AtomicBool::new(false);
+
+/// Where a listener accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix {
+        path: PathBuf,
+        /// Permissions of the socket file, e.g. `0o660`. The umask applies when unset.
+        mode: Option<u32>,
+    },
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A bound socket, accepting connections of any supported transport.
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixSocket),
+}
+
+/// A Unix socket listener, deleting its file once dropped if it created it.
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Inherited sockets belong to whoever bound them.
+    owned: bool,
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if self.owned && !HANDED_OFF.load(Ordering::SeqCst) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// An accepted connection.
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl ListenSocket {
+    /// Binds a listener. IPv6 listeners also accept IPv4 clients, unless `only_v6` is set
+    /// because IPv4 addresses are listened on separately.
+    pub fn bind(addr: &ListenAddr, only_v6: bool) -> Result<ListenSocket> {
+        let socket = match addr {
+            ListenAddr::Tcp(socket_addr) => {
+                ListenSocket::Tcp(Self::bind_tcp(*socket_addr, only_v6)?)
+            }
+            ListenAddr::Unix { path, mode } => ListenSocket::Unix(Self::bind_unix(path, *mode)?),
+        };
+
+        Ok(socket)
+    }
+
+    fn bind_tcp(socket_addr: SocketAddr, only_v6: bool) -> Result<TcpListener> {
+        let bind = || -> io::Result<TcpListener> {
+            let socket = Socket::new(Domain::for_address(socket_addr), Type::STREAM, None)?;
+
+            if socket_addr.is_ipv6() {
+                socket.set_only_v6(only_v6)?;
+            }
+
+            // Like std, so that a restarted server does not wait for TIME_WAIT sockets
+            socket.set_reuse_address(true)?;
+
+            socket.set_nonblocking(true)?;
+            socket.bind(&socket_addr.into())?;
+            socket.listen(1024)?;
+
+            TcpListener::from_std(socket.into())
+        };
+
+        bind().with_context(|| format!("Cannot listen on {}", socket_addr))
+    }
+
+    /// Binds a Unix socket. Its file is created with `mode` right away, under a umask masking
+    /// the other permissions, rather than changed afterwards, as clients could connect in
+    /// between. The umask is shared by the whole process, but nothing else creates files while
+    /// the server starts.
+    fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixSocket> {
+        Self::remove_stale_socket(path)?;
+
+        let bind = || match mode {
+            Some(mode) => {
+                let umask = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+                let listener = UnixListener::bind(path);
+                unsafe { libc::umask(umask) };
+
+                listener
+            }
+            None => UnixListener::bind(path),
+        };
+
+        let listener =
+            bind().with_context(|| format!("Cannot listen on unix:{}", path.display()))?;
+
+        Ok(UnixSocket {
+            listener,
+            path: path.to_path_buf(),
+            owned: true,
+        })
+    }
+
+    /// Deletes a socket file left behind by a server that did not stop cleanly.
+    /// Sockets a server still accepts connections on are left alone.
+    fn remove_stale_socket(path: &Path) -> Result<()> {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path.display()));
+        }
+
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("{} is in use by another server", path.display()));
+        }
+
+        fs::remove_file(path)
+            .with_context(|| format!("Cannot remove stale socket {}", path.display()))
+    }
+
+    /// Takes the listening sockets passed by a supervisor such as systemd, as described in
+    /// sd_listen_fds(3), or by a previous process of this server.
+    ///
+    /// The variables are left in the environment, as other threads may be reading it. Child
+    /// processes cannot take the sockets anyway: `LISTEN_PID` names this process, and the
+    /// sockets are only passed on to a new process of this server, which overrides them.
+    pub fn inherited() -> Result<Vec<ListenSocket>> {
+        if !is_socket_activated() {
+            return Ok(Vec::new());
+        }
+
+        let parent_pid = env::var(PARENT_PID_VAR)
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        PARENT_PID.store(parent_pid.unwrap_or(0), Ordering::SeqCst);
+
+        let from_parent = parent_pid.is_some();
+
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<RawFd>().ok())
+            .ok_or(anyhow!("Invalid LISTEN_FDS"))?;
+
+        // Socket files of a previous process are ours to delete, not those of a supervisor
+        (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                Self::from_fd(fd, from_parent)
+                    .with_context(|| format!("Invalid inherited socket {}", fd))
+            })
+            .collect()
+    }
+
+    fn from_fd(fd: RawFd, owned: bool) -> Result<ListenSocket> {
+        // The supervisor passed the descriptor for this process to own
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+
+        if socket.r#type()? != Type::STREAM || !socket.is_listener()? {
+            return Err(anyhow!("Not a listening stream socket"));
+        }
+
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+
+        let local_addr = socket.local_addr()?;
+
+        if local_addr.is_unix() {
+            let path = local_addr
+                .as_pathname()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+
+            Ok(ListenSocket::Unix(UnixSocket {
+                listener: UnixListener::from_std(socket.into())?,
+                path,
+                owned,
+            }))
+        } else {
+            Ok(ListenSocket::Tcp(TcpListener::from_std(socket.into())?))
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<ListenAddr> {
+        match self {
+            ListenSocket::Tcp(listener) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            ListenSocket::Unix(socket) => Ok(ListenAddr::Unix {
+                path: socket.path.clone(),
+                mode: None,
+            }),
+        }
+    }
+
+    /// Accepts a connection, with the address of the client.
+    pub async fn accept(&self) -> io::Result<(Connection, SocketAddr)> {
+        match self {
+            ListenSocket::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                Ok((Connection::Tcp(stream), peer_addr))
+            }
+            ListenSocket::Unix(socket) => {
+                let (stream, _) = socket.listener.accept().await?;
+                Ok((Connection::Unix(stream), UNIX_PEER_ADDR))
+            }
+        }
+    }
+}
+
+impl AsRawFd for ListenSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ListenSocket::Tcp(listener) => listener.as_raw_fd(),
+            ListenSocket::Unix(socket) => socket.listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Whether an error accepting a connection only concerns that connection, or is due to a
+/// shortage of resources that ends as connections close, so that accepting should go on.
+pub fn is_transient_accept_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::OutOfMemory
+    ) || matches!(
+        error.raw_os_error(),
+        Some(
+            libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EPROTO | libc::EPERM
+        )
+    )
+}
+
+/// Whether a client IP is that given to clients of Unix sockets.
+pub fn is_unix_peer(ip: IpAddr) -> bool {
+    ip == UNIX_PEER_ADDR.ip()
+}
+
+/// Whether listening sockets were passed to this process with `LISTEN_FDS`.
+pub fn is_socket_activated() -> bool {
+    env::var("LISTEN_PID").is_ok_and(|pid| pid == process::id().to_string())
+        || env::var(PARENT_PID_VAR)
+            .is_ok_and(|pid| pid == std::os::unix::process::parent_id().to_string())
+}
+
+/// The PID of the previous process of this server, if it handed the sockets over.
+pub fn handed_over_by() -> Option<u32> {
+    match PARENT_PID.load(Ordering::SeqCst) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// Marks the sockets as handed over to a new process, or back to this one
+/// if the new process failed.
+pub fn set_handed_off(handed_off: bool) {
+    HANDED_OFF.store(handed_off, Ordering::SeqCst);
+}
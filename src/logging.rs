@@ -0,0 +1,28 @@
+use std::io::{self, IsTerminal};
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LoggingConfig;
+
+/// Installs the global subscriber for diagnostics, written to stderr so that they never
+/// mix with an access log on stdout.
+///
+/// The filter uses the `RUST_LOG` syntax, e.g. `info` or `warn,http_server_starter_rust=debug`.
+pub fn init(config: &LoggingConfig) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.filter)
+        .map_err(|e| anyhow!("Invalid log filter {}: {}", config.filter, e))?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+
+    let result = if config.json {
+        builder.json().try_init()
+    } else {
+        builder.try_init()
+    };
+
+    result.map_err(|e| anyhow!("Cannot install the logger: {}", e))
+}
@@ -1,30 +1,118 @@
+pub mod access_log;
 pub mod config;
+pub mod forwarded;
+pub mod health;
+pub mod http2;
+pub mod limits;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
 pub mod middleware;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod reload;
 pub mod request;
+pub mod request_id;
 pub mod response;
 pub mod server;
+pub mod shutdown;
+pub mod sse;
+pub mod static_files;
+pub mod tls;
 pub mod utils;
+pub mod vhost;
+pub mod websocket;
 
-use std::{env, fs};
+use std::{
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
-use config::Config;
+use config::{ArgError, Command};
+use listener::ListenAddr;
 
 use response::ResponseBuilder;
-use server::{RequestInfo, Server};
+use server::{RequestInfo, RouteHandlerFn, Server};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = Config::new(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {}", err);
-        std::process::exit(1);
-    });
-    let addr = format!("127.0.0.1:{}", config.port);
-    let socket_addr = std::net::SocketAddr::V4(addr.parse().unwrap());
-
-    let mut server = Server::new(socket_addr, config).await?;
-
-    server.route_handlers(&[
+    let config = match Command::parse(env::args()) {
+        Ok(Command::Run(config)) => *config,
+        Ok(Command::PrintHelp) => {
+            print!("{}", Command::usage());
+            return Ok(());
+        }
+        Ok(Command::PrintVersion) => {
+            println!("{}", Command::version());
+            return Ok(());
+        }
+        // Usage errors exit with 2, like most command line tools
+        Err(err) if err.is::<ArgError>() => {
+            eprintln!("error: {:#}\n\nFor more information, try '--help'.", err);
+            std::process::exit(2);
+        }
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    logging::init(&config.logging)?;
+
+    let mut listen_addrs = config
+        .bind
+        .iter()
+        .map(|ip| ListenAddr::Tcp(SocketAddr::new(*ip, config.port)))
+        .collect::<Vec<_>>();
+
+    if let Some(path) = &config.unix_socket {
+        listen_addrs.push(ListenAddr::Unix {
+            path: path.clone(),
+            mode: config.unix_socket_mode,
+        });
+    }
+
+    let rate_limit = config.rate_limit.clone();
+    let health_routes = config.health_routes;
+    let cors = config.cors.clone();
+    let request_limits = config.request_limits;
+
+    let mut server = Server::new(&listen_addrs, config).await?;
+
+    // Preflight requests are answered first, and every response, including rate limited
+    // ones, gets the CORS headers browsers need to read it
+    if let Some(cors) = cors {
+        server.middleware(middleware::Cors::new(cors));
+    }
+
+    // Limit clients before doing any work for them, but always answer health probes
+    if let Some(rate_limit) = rate_limit {
+        let mut rate_limiter =
+            middleware::RateLimiter::new(rate_limit.limit, rate_limit.window, rate_limit.key);
+
+        if health_routes {
+            rate_limiter = rate_limiter
+                .exempt(health::HEALTHZ_PATH)
+                .exempt(health::READYZ_PATH);
+        }
+
+        server.middleware(rate_limiter);
+    }
+
+    // Uploads are written to the public directory
+    let pub_dir = PathBuf::from(server.info().pub_dir());
+    server.readiness_check("pub_dir", move || health::dir_writable(pub_dir.clone()));
+
+    // Inflate compressed uploads, capping the decoded size to guard against zip bombs
+    if request_limits.decode_bodies {
+        server.middleware(middleware::RequestDecoder::new(
+            request_limits.max_decoded_body_size,
+        ));
+    }
+
+    let app_routes: &[(&str, RouteHandlerFn)] = &[
         ("GET /", |_| {
             let response = ResponseBuilder::new().status(200, "OK");
             Ok(response)
@@ -63,8 +151,15 @@ async fn main() -> Result<()> {
             Ok(response)
         }),
         ("GET /files/:filename", handle_read_file),
+        ("GET /ws/echo", handle_websocket_echo),
+        ("GET /events/ticks", handle_ticks),
         ("POST /files/:filename", handle_post_file),
-    ])?;
+    ];
+
+    // Every virtual host serves the app, from its own public directory
+    for host in server.virtual_hosts_mut() {
+        host.add_route_handlers(app_routes)?;
+    }
 
     server.run().await
 }
@@ -75,17 +170,7 @@ fn handle_read_file(req_info: RequestInfo) -> Result<ResponseBuilder> {
 
     let path = format!("{}/{}", req_info.pub_dir(), filename);
 
-    let file = fs::read(path);
-
-    let response = match file {
-        Ok(file) => ResponseBuilder::new()
-            .status(200, "OK")
-            .header("Content-Type", "application/octet-stream")
-            .body(&file),
-        Err(_) => ResponseBuilder::new()
-            .status(404, "Not Found")
-            .header("Content-Type", "text/plain"),
-    };
+    let response = static_files::serve_file(request, Path::new(&path), "application/octet-stream");
 
     Ok(response)
 }
@@ -110,3 +195,40 @@ fn handle_post_file(req_info: RequestInfo) -> Result<ResponseBuilder> {
 
     Ok(response)
 }
+
+fn handle_websocket_echo(req_info: RequestInfo) -> Result<ResponseBuilder> {
+    websocket::upgrade(req_info.request(), |mut websocket| async move {
+        while let Some(message) = websocket.recv().await? {
+            websocket.send(message).await?;
+        }
+
+        Ok(())
+    })
+}
+
+fn handle_ticks(req_info: RequestInfo) -> Result<ResponseBuilder> {
+    // Resume counting after the last tick a reconnecting client received
+    let start = sse::last_event_id(req_info.request())
+        .and_then(|id| id.parse::<u64>().ok())
+        .map_or(0, |id| id + 1);
+
+    let (sender, stream) = sse::channel(16);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+        for tick in start.. {
+            interval.tick().await;
+
+            let tick = tick.to_string();
+            let event = sse::Event::new(&tick).id(&tick).event("tick");
+
+            // The client disconnected
+            if sender.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(stream.into_response())
+}
@@ -0,0 +1,246 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::{
+    access_log::RequestLog, limits::ConnectionStats, response::ResponseBuilder, server::RequestInfo,
+};
+
+/// Upper bounds of the request latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds of the upload size buckets, in bytes.
+const UPLOAD_SIZE_BUCKETS: [f64; 6] = [
+    1024.0,
+    10.0 * 1024.0,
+    100.0 * 1024.0,
+    1024.0 * 1024.0,
+    10.0 * 1024.0 * 1024.0,
+    100.0 * 1024.0 * 1024.0,
+];
+
+/// Route label of requests that matched no route, so that scanners probing random paths
+/// do not create a series per path.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Count of observations per bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let series_labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let mut cumulative = 0;
+
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let _ = writeln!(out, "{}_sum{} {}", name, series_labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, series_labels, self.count);
+    }
+}
+
+#[derive(Debug)]
+struct MetricsState {
+    /// Requests by route, method and status.
+    requests: BTreeMap<(String, String, u16), u64>,
+    /// Latencies by route and method.
+    latencies: BTreeMap<(String, String), Histogram>,
+    upload_sizes: Histogram,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// Request counters and histograms, rendered in the Prometheus text exposition format.
+#[derive(Debug)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            state: Mutex::new(MetricsState {
+                requests: BTreeMap::new(),
+                latencies: BTreeMap::new(),
+                upload_sizes: Histogram::new(&UPLOAD_SIZE_BUCKETS),
+                bytes_in: 0,
+                bytes_out: 0,
+            }),
+        }
+    }
+
+    /// Records a request once its response was sent. `bytes` is the size of the response body.
+    pub fn record(&self, request_log: &RequestLog, status: u16, bytes: usize) {
+        let route = request_log.route().unwrap_or(UNMATCHED_ROUTE).to_string();
+        let method = request_log.method().unwrap_or("-").to_string();
+        let latency = request_log.elapsed().as_secs_f64();
+        let bytes_in = request_log.bytes_in();
+
+        let mut state = self.state.lock().unwrap();
+
+        *state
+            .requests
+            .entry((route.clone(), method.clone(), status))
+            .or_default() += 1;
+
+        state
+            .latencies
+            .entry((route, method))
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(latency);
+
+        if bytes_in > 0 {
+            state.upload_sizes.observe(bytes_in as f64);
+        }
+
+        state.bytes_in += bytes_in as u64;
+        state.bytes_out += bytes as u64;
+    }
+
+    pub fn render(&self, connections: &ConnectionStats) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Requests served, by route, method and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((route, method, status), count) in &state.requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                escape(route),
+                method,
+                status,
+                count
+            );
+        }
+
+        out.push_str(
+            "# HELP http_request_duration_seconds Time to serve requests, by route and method.\n",
+        );
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for ((route, method), histogram) in &state.latencies {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape(route), method);
+            histogram.render(&mut out, "http_request_duration_seconds", &labels);
+        }
+
+        out.push_str("# HELP http_request_body_size_bytes Size of request bodies.\n");
+        out.push_str("# TYPE http_request_body_size_bytes histogram\n");
+        state
+            .upload_sizes
+            .render(&mut out, "http_request_body_size_bytes", "");
+
+        let counters = [
+            (
+                "http_request_bytes_total",
+                "Bytes received in request bodies.",
+                "counter",
+                state.bytes_in,
+            ),
+            (
+                "http_response_bytes_total",
+                "Bytes sent in response bodies.",
+                "counter",
+                state.bytes_out,
+            ),
+            (
+                "http_connections_active",
+                "Connections currently being served.",
+                "gauge",
+                connections.active() as u64,
+            ),
+            (
+                "http_connections_queued",
+                "Connections waiting for a free slot.",
+                "gauge",
+                connections.queued() as u64,
+            ),
+            (
+                "http_connections_accepted_total",
+                "Connections accepted.",
+                "counter",
+                connections.accepted(),
+            ),
+            (
+                "http_connections_rejected_total",
+                "Connections rejected at the connection limits.",
+                "counter",
+                connections.rejected(),
+            ),
+        ];
+
+        for (name, help, kind, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
+
+/// Escapes a label value as the exposition format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Route handler serving the metrics of the server.
+pub fn handle_metrics(req_info: RequestInfo) -> Result<ResponseBuilder> {
+    let server_info = req_info.server_info();
+    let metrics = server_info.metrics().render(server_info.connection_stats());
+
+    Ok(ResponseBuilder::new()
+        .status(200, "OK")
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(metrics.as_bytes()))
+}
@@ -1,6 +1,40 @@
-use crate::{request::Request, response::ResponseBuilder, utils::gzip_str};
+use std::{
+    collections::HashMap,
+    io::Read,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{CorsConfig, CorsOrigin, RateLimitKey},
+    listener,
+    request::{HTTPMethod, Request},
+    response::ResponseBuilder,
+    server::RequestInfo,
+    utils::{accepts_encoding, gzip_str},
+};
 
 use anyhow::Result;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+/// A layer registered on the `Server` that runs around every route handler.
+pub trait Middleware: Send + Sync {
+    /// Called before routing. Returning a response short-circuits the request:
+    /// the remaining middlewares and the route handler are skipped.
+    fn handle_request(&self, _req_info: &mut RequestInfo) -> Result<Option<ResponseBuilder>> {
+        Ok(None)
+    }
+
+    /// Called with the response on the way out, in reverse registration order.
+    fn handle_response(
+        &self,
+        _req_info: &RequestInfo,
+        response: ResponseBuilder,
+    ) -> Result<ResponseBuilder> {
+        Ok(response)
+    }
+}
 
 pub fn gzip_response_middleware(
     request: &Request,
@@ -8,13 +42,7 @@ pub fn gzip_response_middleware(
 ) -> Result<ResponseBuilder> {
     let accept_encoding = request.headers().get("Accept-Encoding");
 
-    if accept_encoding.is_some()
-        && accept_encoding
-            .unwrap()
-            .split(',')
-            .map(|x| x.trim())
-            .any(|x| x == "gzip")
-    {
+    if accept_encoding.is_some_and(|x| accepts_encoding(x, "gzip")) {
         let body = response.get_body();
         let compressed_body = gzip_str(String::from_utf8_lossy(body).as_ref())?;
 
@@ -25,3 +53,746 @@ pub fn gzip_response_middleware(
 
     Ok(response)
 }
+
+/// Transparently inflates request bodies sent with `Content-Encoding: gzip` or `deflate`.
+///
+/// Decoded bodies larger than `max_body_size` are rejected with 413, so that a small
+/// compressed payload cannot expand into an arbitrarily large one.
+/// Unsupported encodings are rejected with 415.
+pub struct RequestDecoder {
+    max_body_size: usize,
+}
+
+impl RequestDecoder {
+    pub fn new(max_body_size: usize) -> Self {
+        RequestDecoder { max_body_size }
+    }
+
+    fn decode(&self, encoding: &str, body: &[u8]) -> Result<Option<Vec<u8>>> {
+        // Read one byte past the limit to detect bodies that exceed it
+        let limit = self.max_body_size as u64 + 1;
+        let mut decoded = Vec::new();
+
+        match encoding {
+            "gzip" | "x-gzip" => GzDecoder::new(body).take(limit).read_to_end(&mut decoded)?,
+            "deflate" => {
+                // `deflate` should be zlib-wrapped, but some clients send raw deflate data
+                match ZlibDecoder::new(body).take(limit).read_to_end(&mut decoded) {
+                    Ok(len) => len,
+                    Err(_) => {
+                        decoded.clear();
+                        DeflateDecoder::new(body)
+                            .take(limit)
+                            .read_to_end(&mut decoded)?
+                    }
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(decoded))
+    }
+}
+
+impl Middleware for RequestDecoder {
+    fn handle_request(&self, req_info: &mut RequestInfo) -> Result<Option<ResponseBuilder>> {
+        let request = req_info.request_mut();
+
+        let encodings = match request.headers().get("Content-Encoding") {
+            Some(encodings) => encodings
+                .split(',')
+                .map(|x| x.trim().to_ascii_lowercase())
+                .filter(|x| !x.is_empty() && x != "identity")
+                .collect::<Vec<String>>(),
+            None => return Ok(None),
+        };
+
+        let mut body = request.body().cloned().unwrap_or_default();
+
+        // Codings are listed in the order they were applied, so undo them in reverse
+        for encoding in encodings.iter().rev() {
+            body = match self.decode(encoding, &body) {
+                Ok(Some(decoded)) if decoded.len() > self.max_body_size => {
+                    return Ok(Some(
+                        ResponseBuilder::new()
+                            .status(413, "Payload Too Large")
+                            .header("Content-Type", "text/plain")
+                            .body("Decompressed body too large".as_bytes()),
+                    ));
+                }
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => {
+                    return Ok(Some(
+                        ResponseBuilder::new()
+                            .status(415, "Unsupported Media Type")
+                            .header("Accept-Encoding", "gzip, deflate")
+                            .header("Content-Type", "text/plain")
+                            .body(format!("Unsupported Content-Encoding: {}", encoding).as_bytes()),
+                    ));
+                }
+                Err(_) => {
+                    return Ok(Some(
+                        ResponseBuilder::new()
+                            .status(400, "Bad Request")
+                            .header("Content-Type", "text/plain")
+                            .body("Malformed compressed body".as_bytes()),
+                    ));
+                }
+            };
+        }
+
+        let headers = request.headers_mut();
+        headers.remove("Content-Encoding");
+        headers.insert("Content-Length".to_string(), body.len().to_string());
+
+        request.set_body(body);
+
+        Ok(None)
+    }
+}
+
+/// Buckets kept at most, so that clients making up keys cannot exhaust the memory.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Longest header value keying a bucket.
+const MAX_HEADER_KEY_LEN: usize = 256;
+
+/// Limits how many requests a client can make, with a token bucket per key.
+///
+/// Each bucket holds up to `limit` tokens and refills at `limit` tokens per `window`, so
+/// bursts up to the limit are allowed. Requests over the limit get a 429 response, and every
+/// response carries the `RateLimit-*` headers of its bucket.
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    key: RateLimitKey,
+    /// Paths never limited, like those of health probes.
+    exempt_paths: Vec<String>,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    last_sweep: Instant,
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration, key: RateLimitKey) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            key,
+            exempt_paths: Vec::new(),
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Leaves requests for `path` unlimited, e.g. so that the probes of a load balancer
+    /// sharing its IP address with clients are never turned away.
+    pub fn exempt(mut self, path: &str) -> Self {
+        self.exempt_paths.push(path.to_string());
+        self
+    }
+
+    /// The key of the bucket of a request, if it is limited at all. Clients of Unix sockets
+    /// have no IP address of their own unless a trusted proxy tells it, so are not limited
+    /// by IP.
+    fn key(&self, req_info: &RequestInfo) -> Option<String> {
+        self.request_key(req_info.request(), req_info.client_ip(), req_info.route())
+    }
+
+    fn request_key(&self, request: &Request, ip: IpAddr, route: Option<&str>) -> Option<String> {
+        let path = request.request_line().path();
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+        if self.exempt_paths.iter().any(|exempt| exempt == path) {
+            return None;
+        }
+        let client_ip = || (!listener::is_unix_peer(ip)).then(|| format!("ip:{}", ip));
+
+        match &self.key {
+            RateLimitKey::ClientIp => client_ip(),
+            // Clients without the header, or with a malformed one, share the limit of their
+            // IP address
+            RateLimitKey::Header(name) => match request.headers().get(name) {
+                Some(value) if is_valid_key(value) => Some(format!("header:{}", value)),
+                _ => client_ip(),
+            },
+            // Requests matching no route share a bucket, rather than getting one per path
+            RateLimitKey::Route => Some(format!(
+                "route:{:?} {}",
+                request.method(),
+                route.unwrap_or("-")
+            )),
+        }
+    }
+
+    /// Tokens gained per second.
+    fn rate(&self) -> f64 {
+        self.limit as f64 / self.window.as_secs_f64()
+    }
+
+    /// Refills the bucket of `key`, then takes a token from it if `take` is set and one is
+    /// available. Returns the bucket and whether a token was taken.
+    fn update(&self, key: String, take: bool) -> (Bucket, bool) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Full buckets are the same as missing ones, so drop them once per window, or as
+        // soon as there is no room left for a new one
+        let is_new = !buckets.by_key.contains_key(&key);
+
+        if now.duration_since(buckets.last_sweep) >= self.window
+            || (is_new && buckets.by_key.len() >= MAX_BUCKETS)
+        {
+            let full = self.limit as f64;
+
+            buckets
+                .by_key
+                .retain(|_, bucket| self.refilled(bucket, now) < full);
+            buckets.last_sweep = now;
+        }
+
+        // Past the cap, the bucket closest to full is forgotten, as it is that of the client
+        // least in need of limiting
+        if is_new && buckets.by_key.len() >= MAX_BUCKETS {
+            let fullest = buckets
+                .by_key
+                .iter()
+                .max_by(|(_, a), (_, b)| self.refilled(a, now).total_cmp(&self.refilled(b, now)))
+                .map(|(key, _)| key.clone());
+
+            if let Some(fullest) = fullest {
+                buckets.by_key.remove(&fullest);
+            }
+        }
+
+        let bucket = buckets.by_key.entry(key).or_insert(Bucket {
+            tokens: self.limit as f64,
+            updated: now,
+        });
+
+        bucket.tokens = self.refilled(bucket, now).min(self.limit as f64);
+        bucket.updated = now;
+
+        let taken = take && bucket.tokens >= 1.0;
+        if taken {
+            bucket.tokens -= 1.0;
+        }
+
+        (*bucket, taken)
+    }
+
+    /// The tokens of a bucket once refilled for the time elapsed since its last update,
+    /// before capping them to the limit.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate()
+    }
+
+    fn with_headers(&self, response: ResponseBuilder, bucket: &Bucket) -> ResponseBuilder {
+        // Seconds until the bucket is full again
+        let reset = (self.limit as f64 - bucket.tokens) / self.rate();
+
+        response
+            .header("RateLimit-Limit", &self.limit.to_string())
+            .header("RateLimit-Remaining", &(bucket.tokens as u32).to_string())
+            .header("RateLimit-Reset", &(reset.ceil() as u64).to_string())
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn handle_request(&self, req_info: &mut RequestInfo) -> Result<Option<ResponseBuilder>> {
+        let Some(key) = self.key(req_info) else {
+            return Ok(None);
+        };
+
+        let (bucket, taken) = self.update(key, true);
+
+        if taken {
+            return Ok(None);
+        }
+
+        // Seconds until the next token is available
+        let retry_after = ((1.0 - bucket.tokens) / self.rate()).ceil() as u64;
+
+        let response = ResponseBuilder::new()
+            .status(429, "Too Many Requests")
+            .header("Retry-After", &retry_after.max(1).to_string())
+            .header("Content-Type", "text/plain")
+            .body("Too many requests".as_bytes());
+
+        Ok(Some(self.with_headers(response, &bucket)))
+    }
+
+    fn handle_response(
+        &self,
+        req_info: &RequestInfo,
+        response: ResponseBuilder,
+    ) -> Result<ResponseBuilder> {
+        let Some(key) = self.key(req_info) else {
+            return Ok(response);
+        };
+
+        let (bucket, _) = self.update(key, false);
+
+        Ok(self.with_headers(response, &bucket))
+    }
+}
+
+/// Whether a header value can key a bucket: printable ASCII of a bounded length, as API
+/// keys and tokens are.
+fn is_valid_key(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_HEADER_KEY_LEN
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+}
+
+/// Lets browsers call the server from other origins, as allowed by the configuration.
+///
+/// Preflight requests, `OPTIONS` requests with `Access-Control-Request-Method`, are answered
+/// directly, without reaching the routes. Other responses to allowed origins get the
+/// `Access-Control-Allow-*` headers, and every response varies by `Origin`, so that caches
+/// do not serve the headers of one origin to another.
+pub struct Cors {
+    config: CorsConfig,
+}
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> Self {
+        Cors { config }
+    }
+
+    fn is_allowed(&self, origin: &str) -> bool {
+        let lowercase = origin.to_ascii_lowercase();
+
+        self.config.origins.iter().any(|allowed| match allowed {
+            CorsOrigin::Any => true,
+            CorsOrigin::Exact(allowed) => *allowed == lowercase,
+            CorsOrigin::Wildcard { prefix, suffix } => lowercase
+                .strip_prefix(prefix.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty() && !subdomain.contains(['/', ':', '@'])
+                }),
+            CorsOrigin::Regex(regex) => regex.is_match(origin),
+        })
+    }
+
+    /// The `Access-Control-Allow-Origin` value: `*` when any origin is allowed without
+    /// credentials, the origin itself otherwise.
+    fn allow_origin<'a>(&self, origin: &'a str) -> &'a str {
+        let any = self
+            .config
+            .origins
+            .iter()
+            .any(|allowed| matches!(allowed, CorsOrigin::Any));
+
+        if any && !self.config.credentials {
+            "*"
+        } else {
+            origin
+        }
+    }
+
+    fn preflight(&self, request: &Request, origin: &str) -> ResponseBuilder {
+        let headers = request.headers();
+
+        let method = headers
+            .get("Access-Control-Request-Method")
+            .map_or("", |method| method.trim());
+        let method_allowed = self.config.methods.iter().any(|allowed| allowed == method);
+
+        let requested_headers = headers
+            .get("Access-Control-Request-Headers")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let headers_allowed = match &self.config.headers {
+            None => true,
+            Some(allowed) => requested_headers.iter().all(|name| {
+                allowed
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(name))
+            }),
+        };
+
+        if !self.is_allowed(origin) || !method_allowed || !headers_allowed {
+            return ResponseBuilder::new()
+                .status(403, "Forbidden")
+                .header("Content-Type", "text/plain")
+                .header("Vary", "Origin")
+                .body("CORS request not allowed".as_bytes());
+        }
+
+        let allow_headers = match &self.config.headers {
+            // Allowing what was asked for allows any header
+            None => requested_headers.join(", "),
+            Some(allowed) => allowed.join(", "),
+        };
+
+        let mut response = ResponseBuilder::new()
+            .status(204, "No Content")
+            .header("Access-Control-Allow-Origin", self.allow_origin(origin))
+            .header(
+                "Access-Control-Allow-Methods",
+                &self.config.methods.join(", "),
+            )
+            .header(
+                "Access-Control-Max-Age",
+                &self.config.max_age.as_secs().to_string(),
+            )
+            .header(
+                "Vary",
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+            );
+
+        if !allow_headers.is_empty() {
+            response = response.header("Access-Control-Allow-Headers", &allow_headers);
+        }
+        if self.config.credentials {
+            response = response.header("Access-Control-Allow-Credentials", "true");
+        }
+
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn handle_request(&self, req_info: &mut RequestInfo) -> Result<Option<ResponseBuilder>> {
+        let request = req_info.request();
+
+        match request.headers().get("Origin") {
+            Some(origin) if is_preflight(request) => Ok(Some(self.preflight(request, origin))),
+            _ => Ok(None),
+        }
+    }
+
+    fn handle_response(
+        &self,
+        req_info: &RequestInfo,
+        response: ResponseBuilder,
+    ) -> Result<ResponseBuilder> {
+        // Preflight responses are complete already
+        if is_preflight(req_info.request()) {
+            return Ok(response);
+        }
+
+        let vary = match response.get_header("Vary") {
+            Some(vary)
+                if vary
+                    .split(',')
+                    .any(|name| name.trim().eq_ignore_ascii_case("Origin")) =>
+            {
+                vary.to_string()
+            }
+            Some(vary) => format!("{}, Origin", vary),
+            None => "Origin".to_string(),
+        };
+        let mut response = response.header("Vary", &vary);
+
+        let origin = match req_info.request().headers().get("Origin") {
+            Some(origin) if self.is_allowed(origin) => origin,
+            _ => return Ok(response),
+        };
+
+        response = response.header("Access-Control-Allow-Origin", self.allow_origin(origin));
+
+        if self.config.credentials {
+            response = response.header("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.config.expose_headers.is_empty() {
+            response = response.header(
+                "Access-Control-Expose-Headers",
+                &self.config.expose_headers.join(", "),
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+fn is_preflight(request: &Request) -> bool {
+    request.method() == &HTTPMethod::OPTIONS
+        && request.headers().contains_key("Origin")
+        && request
+            .headers()
+            .contains_key("Access-Control-Request-Method")
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{config::Config, listener::UNIX_PEER_ADDR};
+
+    const CLIENT: &str = "198.51.100.1";
+
+    fn request(path: &str, headers: &[&str]) -> Request {
+        let head = [format!("GET {} HTTP/1.1", path)]
+            .into_iter()
+            .chain(headers.iter().map(|header| header.to_string()))
+            .map(|line| format!("{}\r\n", line))
+            .collect::<String>();
+
+        Request::parse_head(&head).unwrap()
+    }
+
+    fn key(limiter: &RateLimiter, path: &str, headers: &[&str]) -> Option<String> {
+        limiter.request_key(&request(path, headers), CLIENT.parse().unwrap(), None)
+    }
+
+    #[test]
+    fn takes_tokens_until_the_bucket_is_empty() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60), RateLimitKey::ClientIp);
+
+        assert!(limiter.update("a".to_string(), true).1);
+        assert!(limiter.update("a".to_string(), true).1);
+        assert!(!limiter.update("a".to_string(), true).1);
+
+        // Other keys have buckets of their own
+        assert!(limiter.update("b".to_string(), true).1);
+    }
+
+    #[test]
+    fn refills_buckets_over_the_window_up_to_the_limit() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(10), RateLimitKey::ClientIp);
+        let now = Instant::now();
+
+        let bucket = |tokens, elapsed| Bucket {
+            tokens,
+            updated: now - Duration::from_millis(elapsed),
+        };
+
+        assert_eq!(limiter.refilled(&bucket(0.0, 2500), now), 2.5);
+
+        limiter
+            .buckets
+            .lock()
+            .unwrap()
+            .by_key
+            .insert("a".to_string(), bucket(0.0, 1500));
+
+        let (refilled, taken) = limiter.update("a".to_string(), true);
+        assert!(taken);
+        assert!((0.5..0.6).contains(&refilled.tokens));
+
+        limiter
+            .buckets
+            .lock()
+            .unwrap()
+            .by_key
+            .insert("a".to_string(), bucket(5.0, 60_000));
+
+        assert_eq!(limiter.update("a".to_string(), false).0.tokens, 10.0);
+    }
+
+    #[test]
+    fn forgets_the_fullest_bucket_past_the_cap() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(60), RateLimitKey::ClientIp);
+        let now = Instant::now();
+
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+
+            for index in 0..MAX_BUCKETS {
+                let tokens = if index == 42 { 5.0 } else { 0.0 };
+                let bucket = Bucket {
+                    tokens,
+                    updated: now,
+                };
+
+                buckets.by_key.insert(index.to_string(), bucket);
+            }
+        }
+
+        limiter.update("new".to_string(), true);
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), MAX_BUCKETS);
+        assert!(buckets.by_key.contains_key("new"));
+        assert!(!buckets.by_key.contains_key("42"));
+    }
+
+    #[test]
+    fn exempts_paths_whatever_their_query_string() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(60), RateLimitKey::ClientIp)
+            .exempt("/healthz");
+
+        assert_eq!(key(&limiter, "/healthz", &[]), None);
+        assert_eq!(key(&limiter, "/healthz?probe=1", &[]), None);
+        assert_eq!(
+            key(&limiter, "/healthz/x", &[]),
+            Some(format!("ip:{}", CLIENT))
+        );
+    }
+
+    #[test]
+    fn keys_by_valid_header_values_only() {
+        let limiter = RateLimiter::new(
+            10,
+            Duration::from_secs(60),
+            RateLimitKey::Header("X-Api-Key".to_string()),
+        );
+        let by_ip = Some(format!("ip:{}", CLIENT));
+
+        assert_eq!(
+            key(&limiter, "/", &["x-api-key: Bearer abc"]),
+            Some("header:Bearer abc".to_string())
+        );
+        assert_eq!(key(&limiter, "/", &[]), by_ip);
+        assert_eq!(key(&limiter, "/", &["X-Api-Key: é"]), by_ip);
+
+        let long = format!("X-Api-Key: {}", "k".repeat(MAX_HEADER_KEY_LEN + 1));
+        assert_eq!(key(&limiter, "/", &[&long]), by_ip);
+    }
+
+    #[test]
+    fn leaves_unix_socket_clients_unlimited_by_ip() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(60), RateLimitKey::ClientIp);
+
+        assert_eq!(
+            limiter.request_key(&request("/", &[]), UNIX_PEER_ADDR.ip(), None),
+            None
+        );
+    }
+
+    #[test]
+    fn keys_by_route_pattern() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(60), RateLimitKey::Route);
+        let request = request("/files/a.txt", &[]);
+        let ip = CLIENT.parse().unwrap();
+
+        assert_eq!(
+            limiter.request_key(&request, ip, Some("/files/:filename")),
+            Some("route:GET /files/:filename".to_string())
+        );
+        assert_eq!(
+            limiter.request_key(&request, ip, None),
+            Some("route:GET -".to_string())
+        );
+    }
+
+    fn cors(args: &[&str]) -> Cors {
+        let args = args.iter().map(|arg| arg.to_string());
+
+        Cors::new(Config::new(args).unwrap().cors.unwrap())
+    }
+
+    #[test]
+    fn allows_exact_origins_whatever_their_case() {
+        let cors = cors(&["--cors-origin", "https://App.example.com"]);
+
+        assert!(cors.is_allowed("https://app.example.com"));
+        assert!(cors.is_allowed("HTTPS://APP.EXAMPLE.COM"));
+        assert!(!cors.is_allowed("http://app.example.com"));
+        assert!(!cors.is_allowed("https://app.example.com:8443"));
+        assert!(!cors.is_allowed("https://app.example.com.evil.test"));
+    }
+
+    #[test]
+    fn allows_subdomains_of_wildcard_origins_only() {
+        let cors = cors(&["--cors-origin", "https://*.example.com"]);
+
+        assert!(cors.is_allowed("https://app.example.com"));
+        assert!(cors.is_allowed("https://a.b.example.com"));
+        assert!(!cors.is_allowed("https://example.com"));
+        assert!(!cors.is_allowed("https://.example.com"));
+        assert!(!cors.is_allowed("https://evilexample.com"));
+        assert!(!cors.is_allowed("https://evil.test/.example.com"));
+        assert!(!cors.is_allowed("https://user@evil.test:.example.com"));
+        assert!(!cors.is_allowed("http://app.example.com"));
+    }
+
+    #[test]
+    fn allows_origins_matching_a_whole_pattern() {
+        let cors = cors(&["--cors-origin", r"regex:https://pr-[0-9]+\.example\.dev"]);
+
+        assert!(cors.is_allowed("https://pr-42.example.dev"));
+        assert!(!cors.is_allowed("https://pr-42.example.dev.evil.test"));
+        assert!(!cors.is_allowed("https://evil.test/https://pr-42.example.dev"));
+    }
+
+    #[test]
+    fn answers_any_origin_with_a_star_and_others_with_themselves() {
+        let any = cors(&["--cors-origin", "*"]);
+        assert!(any.is_allowed("https://anything.test"));
+        assert_eq!(any.allow_origin("https://anything.test"), "*");
+
+        let credentials = cors(&[
+            "--cors-origin",
+            "https://app.example.com",
+            "--cors-credentials",
+            "on",
+        ]);
+        assert_eq!(
+            credentials.allow_origin("https://app.example.com"),
+            "https://app.example.com"
+        );
+
+        // Browsers refuse credentials for any origin
+        let args = ["--cors-origin", "*", "--cors-credentials", "on"];
+        assert!(Config::new(args.iter().map(|arg| arg.to_string())).is_err());
+    }
+
+    #[test]
+    fn answers_preflights_for_allowed_methods_and_headers() {
+        let cors = cors(&[
+            "--cors-origin",
+            "https://app.example.com",
+            "--cors-methods",
+            "GET,PUT",
+            "--cors-headers",
+            "Content-Type",
+        ]);
+        let origin = "https://app.example.com";
+
+        let preflight = |headers: &[&str]| {
+            let request = request("/", headers);
+            cors.preflight(&request, origin).build().unwrap()
+        };
+
+        let allowed = preflight(&[
+            "Access-Control-Request-Method: PUT",
+            "Access-Control-Request-Headers: content-type",
+        ]);
+        assert_eq!(allowed.status_code(), 204);
+        assert_eq!(
+            allowed
+                .headers()
+                .get("Access-Control-Allow-Origin")
+                .map(String::as_str),
+            Some(origin)
+        );
+
+        assert_eq!(
+            preflight(&["Access-Control-Request-Method: DELETE"]).status_code(),
+            403
+        );
+        assert_eq!(
+            preflight(&[
+                "Access-Control-Request-Method: GET",
+                "Access-Control-Request-Headers: X-Secret",
+            ])
+            .status_code(),
+            403
+        );
+    }
+}
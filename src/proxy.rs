@@ -0,0 +1,1031 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    runtime::Handle,
+    sync::mpsc,
+    time::{interval, timeout},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::ProxyConfig,
+    forwarded::Client,
+    request::{canonical_header_name, HTTPMethod, Request},
+    response::ResponseBuilder,
+    server::{self, RequestInfo},
+    shutdown::Shutdown,
+};
+
+/// How long connecting to an upstream may take before the next one is tried.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an upstream may take to send the response head, and each part of its body.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Larger response heads are rejected, like the server does for request heads.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Headers that only describe a single connection, so are never forwarded.
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Te",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Headers describing the client, set by the proxy itself.
+const FORWARDING: [&str; 4] = [
+    "Forwarded",
+    "X-Forwarded-For",
+    "X-Forwarded-Host",
+    "X-Forwarded-Proto",
+];
+
+/// Methods a proxy route forwards.
+pub const METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+/// Checks an upstream URL like `http://127.0.0.1:3000`, returning its `host:port`.
+pub fn parse_upstream(url: &str) -> Result<String> {
+    let authority = url
+        .strip_prefix("http://")
+        .ok_or(anyhow!("Expected an http:// URL, got {}", url))?
+        .trim_end_matches('/');
+
+    if authority.is_empty() || authority.contains(['/', '?', '#', '@', ' ']) {
+        return Err(anyhow!(
+            "Invalid upstream {}, expected a URL like http://127.0.0.1:3000",
+            url
+        ));
+    }
+
+    // IPv6 addresses are bracketed, e.g. [::1]:3000
+    match authority.rsplit_once(':') {
+        Some((_, port)) if !port.contains(']') => {
+            port.parse::<u16>()
+                .map_err(|_| anyhow!("Invalid port in upstream {}", url))?;
+            Ok(authority.to_string())
+        }
+        _ => Ok(format!("{}:80", authority)),
+    }
+}
+
+#[derive(Debug)]
+struct Upstream {
+    /// Where to connect, as `host:port`, also sent as the `Host` of forwarded requests.
+    authority: String,
+    healthy: AtomicBool,
+}
+
+/// Forwards requests to upstream HTTP servers, taking turns between them.
+///
+/// Every request opens its own upstream connection. Response bodies are streamed to the
+/// client as they arrive.
+///
+/// Request bodies are not streamed: the server reads the whole body before routing the
+/// request, within the body read timeout and like for any other route, and it is only then
+/// forwarded, in one piece.
+#[derive(Debug)]
+pub struct Proxy {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    health_check: Option<String>,
+    health_interval: Duration,
+}
+
+impl Proxy {
+    pub fn new(config: &ProxyConfig) -> Proxy {
+        Proxy {
+            upstreams: config
+                .upstreams
+                .iter()
+                .map(|authority| Upstream {
+                    authority: authority.clone(),
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            health_check: config.health_check.clone(),
+            health_interval: config.health_interval,
+        }
+    }
+
+    /// Polls the health check path of every upstream until the server shuts down.
+    /// Upstreams answering with anything but a 2xx or 3xx status are skipped until they
+    /// pass again.
+    pub async fn check_health(self: Arc<Self>, mut shutdown: Shutdown) {
+        let Some(path) = &self.health_check else {
+            return;
+        };
+
+        let mut ticks = interval(self.health_interval);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.wait() => return,
+            }
+
+            for upstream in &self.upstreams {
+                let result = timeout(CONNECT_TIMEOUT, probe(&upstream.authority, path)).await;
+
+                match result {
+                    Ok(Ok(status)) if (200..400).contains(&status) => {
+                        self.set_healthy(upstream, true)
+                    }
+                    Ok(Ok(status)) => {
+                        debug!(upstream = upstream.authority, status, "Health check failed");
+                        self.set_healthy(upstream, false)
+                    }
+                    Ok(Err(e)) => {
+                        debug!(
+                            upstream = upstream.authority,
+                            "Health check failed: {:#}", e
+                        );
+                        self.set_healthy(upstream, false)
+                    }
+                    Err(_) => {
+                        debug!(upstream = upstream.authority, "Health check timed out");
+                        self.set_healthy(upstream, false)
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_healthy(&self, upstream: &Upstream, healthy: bool) {
+        if upstream.healthy.swap(healthy, Ordering::Relaxed) == healthy {
+            return;
+        }
+
+        if healthy {
+            info!(upstream = upstream.authority, "Upstream is healthy again");
+        } else {
+            warn!(upstream = upstream.authority, "Upstream is unhealthy");
+        }
+    }
+
+    /// The upstreams in the order to try them for the next request: round-robin among the
+    /// healthy ones, then the unhealthy ones as a last resort.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) = self
+            .upstreams
+            .iter()
+            .partition(|upstream| upstream.healthy.load(Ordering::Relaxed));
+
+        if !healthy.is_empty() {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+            healthy.rotate_left(start);
+        }
+
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    /// Forwards a request, answering 502 if no upstream could produce a response.
+    /// Upstreams refusing the connection are skipped for the next one, since they
+    /// have not seen the request.
+    pub async fn forward(&self, req_info: &RequestInfo) -> Result<ResponseBuilder> {
+        let peer_ip = req_info.peer_addr().ip().to_canonical();
+        let trusted_peer = req_info.server_info().trusted_proxies().is_trusted(peer_ip);
+
+        self.forward_request(req_info.request(), req_info.client(), peer_ip, trusted_peer)
+            .await
+    }
+
+    /// Forwards a request received from `peer_ip`. The forwarding headers of earlier proxies
+    /// are only passed on when the peer is a trusted proxy, as any client can send them.
+    async fn forward_request(
+        &self,
+        request: &Request,
+        client: &Client,
+        peer_ip: IpAddr,
+        trusted_peer: bool,
+    ) -> Result<ResponseBuilder> {
+        let request_id = request.request_id().unwrap_or_default();
+
+        for upstream in self.candidates() {
+            let stream =
+                match timeout(CONNECT_TIMEOUT, TcpStream::connect(&upstream.authority)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        warn!(
+                            upstream = upstream.authority,
+                            "Cannot connect to upstream: {}", e
+                        );
+                        self.connect_failed(upstream);
+                        continue;
+                    }
+                    Err(_) => {
+                        warn!(
+                            upstream = upstream.authority,
+                            "Connecting to upstream timed out"
+                        );
+                        self.connect_failed(upstream);
+                        continue;
+                    }
+                };
+
+            let head = request_head(request, client, peer_ip, trusted_peer, upstream);
+
+            return match exchange(stream, upstream, request, &head).await {
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    warn!(upstream = upstream.authority, "Upstream error: {:#}", e);
+                    Ok(server::error_response(502, "Bad Gateway", request_id))
+                }
+            };
+        }
+
+        Ok(server::error_response(502, "Bad Gateway", request_id))
+    }
+
+    /// Without health checks, an upstream marked unhealthy would never be marked healthy
+    /// again, so failures only reorder the upstreams when they are checked.
+    fn connect_failed(&self, upstream: &Upstream) {
+        if self.health_check.is_some() {
+            self.set_healthy(upstream, false);
+        }
+    }
+}
+
+/// Route handler forwarding the requests of a proxy route from the configuration.
+pub fn handle_proxy(req_info: RequestInfo) -> Result<ResponseBuilder> {
+    let Some(proxy) = req_info
+        .route()
+        .and_then(|route| req_info.host().proxy(route))
+    else {
+        return Ok(ResponseBuilder::new().status(404, "Not Found"));
+    };
+
+    // Handlers run on the blocking thread pool, where waiting on the runtime is allowed
+    Handle::current().block_on(proxy.forward(&req_info))
+}
+
+async fn exchange(
+    mut stream: TcpStream,
+    upstream: &Upstream,
+    request: &Request,
+    head: &str,
+) -> Result<ResponseBuilder> {
+    let body = request.body().map_or(&[][..], Vec::as_slice);
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut reader = BufReader::new(stream);
+
+    // Interim responses, like 100 Continue, are not relayed
+    let (status, reason, headers) = loop {
+        let head = timeout(READ_TIMEOUT, read_head(&mut reader))
+            .await
+            .context("Upstream response timed out")??;
+
+        if !(100..200).contains(&head.0) {
+            break head;
+        }
+    };
+
+    let mut response = ResponseBuilder::new().status(status, &reason);
+
+    let connection_headers = connection_headers(
+        headers
+            .iter()
+            .find(|(name, _)| name == "Connection")
+            .map(|(name, value)| (name, value)),
+    );
+
+    // Repeated headers are combined, as responses hold one value per header, except for
+    // cookies, whose values may contain commas (RFC 6265, section 3)
+    let mut combined: Vec<(&str, String)> = Vec::new();
+
+    for (name, value) in &headers {
+        if is_hop_by_hop(name, &connection_headers) || name == "Content-Length" {
+            continue;
+        }
+
+        if name == "Set-Cookie" {
+            response = response.append_header(name, value);
+            continue;
+        }
+
+        match combined
+            .iter_mut()
+            .find(|(combined_name, _)| combined_name == name)
+        {
+            Some((_, combined_value)) => {
+                combined_value.push_str(", ");
+                combined_value.push_str(value);
+            }
+            None => combined.push((name, value.clone())),
+        }
+    }
+
+    for (name, value) in combined {
+        response = response.header(name, &value);
+    }
+
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "Content-Length")
+        .map(|(_, length)| length.parse::<usize>())
+        .transpose()
+        .context("Invalid upstream Content-Length")?;
+
+    // Responses to HEAD requests and these statuses have no body, whatever their headers say.
+    // The length they announce is still that of the resource, so is passed on.
+    if request.method() == &HTTPMethod::HEAD || status == 204 || status == 304 {
+        return Ok(match content_length {
+            Some(length) if status != 204 => response.header("Content-Length", &length.to_string()),
+            _ => response,
+        });
+    }
+
+    let chunked = headers
+        .iter()
+        .any(|(name, value)| name == "Transfer-Encoding" && value.contains("chunked"));
+
+    // Bodies of a known length are relayed as they are, the others chunked
+    let framing = match content_length {
+        _ if chunked => Framing::Chunked,
+        Some(length) => {
+            response = response.header("Content-Length", &length.to_string());
+            Framing::Length(length)
+        }
+        None => Framing::Close,
+    };
+
+    let (body_tx, body_rx) = mpsc::channel(16);
+
+    let authority = upstream.authority.clone();
+    tokio::spawn(async move {
+        // The client sees a truncated body, as the status was already sent
+        if let Err(e) = copy_body(reader, framing, body_tx).await {
+            debug!(upstream = authority, "Upstream body error: {:#}", e);
+        }
+    });
+
+    Ok(response.body_stream(body_rx))
+}
+
+/// The head of the forwarded request, announcing the client in the forwarding headers.
+fn request_head(
+    request: &Request,
+    client: &Client,
+    peer_ip: IpAddr,
+    trusted_peer: bool,
+    upstream: &Upstream,
+) -> String {
+    let headers = request.headers();
+
+    let mut head = format!(
+        "{:?} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        request.method(),
+        request.request_line().path(),
+        upstream.authority
+    );
+
+    let connection_headers = connection_headers(headers.get_key_value("Connection"));
+
+    // Names are forwarded as the client sent them
+    for (name, value) in headers.iter().sorted() {
+        let canonical = canonical_header_name(name);
+
+        if is_hop_by_hop(&canonical, &connection_headers)
+            || FORWARDING.contains(&canonical.as_str())
+            || canonical == "Host"
+            || canonical == "Content-Length"
+        {
+            continue;
+        }
+
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    if let Some(body) = request.body() {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+
+    let scheme = client.scheme;
+    let host = client.host.as_deref();
+
+    // Earlier proxies are kept, the peer of this one coming last. Chains sent by untrusted
+    // peers may be forged, so a new one is started instead.
+    let append = |header: &str, value: String| match headers.get(header) {
+        Some(previous) if trusted_peer => format!("{}, {}", previous, value),
+        _ => value,
+    };
+
+    head.push_str(&format!(
+        "X-Forwarded-For: {}\r\n",
+        append("X-Forwarded-For", peer_ip.to_string())
+    ));
+    head.push_str(&format!("X-Forwarded-Proto: {}\r\n", scheme));
+
+    let mut forwarded = format!("for={};proto={}", forwarded_node(peer_ip), scheme);
+
+    if let Some(host) = host {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        forwarded.push_str(&format!(";host=\"{}\"", host.replace(['"', '\\'], "")));
+    }
+
+    head.push_str(&format!(
+        "Forwarded: {}\r\n\r\n",
+        append("Forwarded", forwarded)
+    ));
+
+    head
+}
+
+/// IPv6 addresses are quoted and bracketed in `Forwarded` (RFC 7239, section 6).
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// Headers listed in `Connection`, which apply to the connection only like hop-by-hop ones.
+fn connection_headers(connection: Option<(&String, &String)>) -> Vec<String> {
+    connection.map_or(Vec::new(), |(_, value)| {
+        value
+            .split(',')
+            .map(|name| canonical_header_name(name.trim()))
+            .collect()
+    })
+}
+
+fn is_hop_by_hop(name: &str, connection_headers: &[String]) -> bool {
+    HOP_BY_HOP.contains(&name) || connection_headers.iter().any(|header| header == name)
+}
+
+/// Reads a response head, returning its status, reason and headers in order.
+async fn read_head(
+    reader: &mut BufReader<TcpStream>,
+) -> Result<(u16, String, Vec<(String, String)>)> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line).await?;
+        size += read;
+
+        if read == 0 {
+            return Err(anyhow!("Upstream closed the connection"));
+        }
+        if size > MAX_HEAD_SIZE {
+            return Err(anyhow!("Upstream response head too large"));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+
+        if line.is_empty() {
+            break;
+        }
+
+        lines.push(line);
+    }
+
+    let status_line = lines.remove(0);
+    let mut parts = status_line.splitn(3, ' ');
+
+    let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("Invalid upstream status line: {}", status_line));
+    };
+
+    if !version.starts_with("HTTP/1.") {
+        return Err(anyhow!("Invalid upstream status line: {}", status_line));
+    }
+
+    let status = status
+        .parse::<u16>()
+        .map_err(|_| anyhow!("Invalid upstream status line: {}", status_line))?;
+    let reason = parts.next().unwrap_or_default().to_string();
+
+    let headers = lines
+        .iter()
+        .map(|line| {
+            line.split_once(':')
+                .map(|(name, value)| (canonical_header_name(name.trim()), value.trim().to_string()))
+                .ok_or(anyhow!("Invalid upstream header: {}", line))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((status, reason, headers))
+}
+
+/// How the end of an upstream response body is found.
+enum Framing {
+    Length(usize),
+    Chunked,
+    /// The body ends when the upstream closes the connection.
+    Close,
+}
+
+/// Streams a response body from the upstream, until the body ends or the client goes away.
+async fn copy_body(
+    mut reader: BufReader<TcpStream>,
+    framing: Framing,
+    body_tx: mpsc::Sender<Vec<u8>>,
+) -> Result<()> {
+    match framing {
+        Framing::Length(length) => copy_exact(&mut reader, length, &body_tx).await,
+        Framing::Close => loop {
+            let mut chunk = vec![0; 16 * 1024];
+            let read = timeout(READ_TIMEOUT, reader.read(&mut chunk))
+                .await
+                .context("Upstream body timed out")??;
+
+            if read == 0 {
+                return Ok(());
+            }
+
+            chunk.truncate(read);
+            body_tx.send(chunk).await?;
+        },
+        Framing::Chunked => loop {
+            let mut line = String::new();
+            timeout(READ_TIMEOUT, reader.read_line(&mut line))
+                .await
+                .context("Upstream body timed out")??;
+
+            // Chunk extensions follow the size after a semicolon
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| anyhow!("Invalid upstream chunk size: {}", line.trim()))?;
+
+            if size == 0 {
+                // Trailers are dropped
+                return Ok(());
+            }
+
+            copy_exact(&mut reader, size, &body_tx).await?;
+
+            let mut crlf = [0; 2];
+            timeout(READ_TIMEOUT, reader.read_exact(&mut crlf))
+                .await
+                .context("Upstream body timed out")??;
+        },
+    }
+}
+
+async fn copy_exact(
+    reader: &mut BufReader<TcpStream>,
+    length: usize,
+    body_tx: &mpsc::Sender<Vec<u8>>,
+) -> Result<()> {
+    let mut remaining = length;
+
+    while remaining > 0 {
+        let mut chunk = vec![0; remaining.min(16 * 1024)];
+        let read = timeout(READ_TIMEOUT, reader.read(&mut chunk))
+            .await
+            .context("Upstream body timed out")??;
+
+        if read == 0 {
+            return Err(anyhow!("Upstream closed the connection mid-body"));
+        }
+
+        chunk.truncate(read);
+        body_tx.send(chunk).await?;
+        remaining -= read;
+    }
+
+    Ok(())
+}
+
+/// Requests the health check path of an upstream, returning the response status.
+async fn probe(authority: &str, path: &str) -> Result<u16> {
+    let mut stream = TcpStream::connect(authority).await?;
+
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                path, authority
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let (status, _, _) = read_head(&mut BufReader::new(stream)).await?;
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{atomic::AtomicU16, Mutex},
+    };
+
+    use tokio::{
+        net::TcpListener,
+        time::{sleep, Instant},
+    };
+
+    use super::*;
+    use crate::{response::Response, shutdown::ShutdownHandle};
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+    /// Starts an upstream answering every connection with `respond`, called with the request
+    /// head it received. Returns its `host:port` and the heads received so far.
+    async fn upstream<F>(respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let authority = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(respond);
+
+        let heads = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let heads = heads.clone();
+                let respond = respond.clone();
+
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    let mut head = String::new();
+
+                    while !head.ends_with("\r\n\r\n") {
+                        if reader.read_line(&mut head).await.unwrap() == 0 {
+                            return;
+                        }
+                    }
+
+                    let response = respond(&head);
+                    heads.lock().unwrap().push(head);
+
+                    let mut stream = reader.into_inner();
+                    stream.write_all(&response).await.unwrap();
+                });
+            }
+        });
+
+        (authority, received)
+    }
+
+    /// An address nothing listens on, so that connecting is refused.
+    async fn refusing_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn proxy(upstreams: &[&str], health_check: Option<&str>) -> Proxy {
+        Proxy::new(&ProxyConfig {
+            upstreams: upstreams
+                .iter()
+                .map(|upstream| upstream.to_string())
+                .collect(),
+            health_check: health_check.map(str::to_string),
+            health_interval: Duration::from_millis(10),
+        })
+    }
+
+    fn request(head: &str) -> Request {
+        Request::parse_head(head).unwrap()
+    }
+
+    fn client() -> Client {
+        Client {
+            ip: PEER,
+            scheme: "https",
+            host: Some("example.com".to_string()),
+        }
+    }
+
+    async fn forward(proxy: &Proxy, request: &Request, trusted_peer: bool) -> (Response, String) {
+        let mut response = proxy
+            .forward_request(request, &client(), PEER, trusted_peer)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let mut body = Vec::new();
+
+        if let Some(mut body_stream) = response.take_body_stream() {
+            while let Some(chunk) = body_stream.recv().await {
+                body.extend_from_slice(&chunk);
+            }
+        }
+
+        (response, String::from_utf8(body).unwrap())
+    }
+
+    fn ok(body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn takes_turns_between_upstreams() {
+        let (first, _) = upstream(|_| ok("first")).await;
+        let (second, _) = upstream(|_| ok("second")).await;
+        let proxy = proxy(&[&first, &second], None);
+        let request = request("GET / HTTP/1.1\r\nHost: example.com");
+
+        let mut bodies = Vec::new();
+        for _ in 0..4 {
+            bodies.push(forward(&proxy, &request, false).await.1);
+        }
+
+        assert_eq!(bodies, ["first", "second", "first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn skips_upstreams_refusing_connections() {
+        let refusing = refusing_upstream().await;
+        let (accepting, received) = upstream(|_| ok("accepted")).await;
+        let proxy = proxy(&[&refusing, &accepting], None);
+        let request = request("GET / HTTP/1.1\r\nHost: example.com");
+
+        for _ in 0..2 {
+            let (response, body) = forward(&proxy, &request, false).await;
+
+            assert_eq!(response.status_code(), 200);
+            assert_eq!(body, "accepted");
+        }
+
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn answers_502_when_no_upstream_accepts() {
+        let refusing = refusing_upstream().await;
+        let proxy = proxy(&[&refusing], None);
+        let request = request("GET / HTTP/1.1\r\nHost: example.com");
+
+        let (response, _) = forward(&proxy, &request, false).await;
+
+        assert_eq!(response.status_code(), 502);
+    }
+
+    #[tokio::test]
+    async fn skips_upstreams_failing_health_checks_until_they_pass() {
+        let status = Arc::new(AtomicU16::new(200));
+        let health_status = status.clone();
+        let (authority, _) = upstream(move |head| {
+            let status = health_status.load(Ordering::Relaxed);
+
+            match head.starts_with("GET /healthz ") {
+                true => {
+                    format!("HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status).into_bytes()
+                }
+                false => ok("served"),
+            }
+        })
+        .await;
+
+        let proxy = Arc::new(proxy(&[&authority], Some("/healthz")));
+        let shutdown = ShutdownHandle::new();
+        tokio::spawn(proxy.clone().check_health(shutdown.subscribe()));
+
+        let healthy = |healthy: bool| {
+            let proxy = proxy.clone();
+
+            async move {
+                let deadline = Instant::now() + Duration::from_secs(5);
+
+                while proxy.upstreams[0].healthy.load(Ordering::Relaxed) != healthy {
+                    assert!(Instant::now() < deadline, "Health never became {}", healthy);
+                    sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+
+        status.store(503, Ordering::Relaxed);
+        healthy(false).await;
+
+        // Unhealthy upstreams are still tried when no other is left
+        let request = request("GET / HTTP/1.1\r\nHost: example.com");
+        assert_eq!(forward(&proxy, &request, false).await.1, "served");
+
+        status.store(200, Ordering::Relaxed);
+        healthy(true).await;
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn strips_hop_by_hop_headers() {
+        let (authority, received) = upstream(|_| {
+            b"HTTP/1.1 200 OK\r\n\
+              Connection: keep-alive, X-Upstream-Hop\r\n\
+              Keep-Alive: timeout=5\r\n\
+              X-Upstream-Hop: 1\r\n\
+              X-Upstream-End: 1\r\n\
+              Content-Length: 0\r\n\r\n"
+                .to_vec()
+        })
+        .await;
+        let proxy = proxy(&[&authority], None);
+        let request = request(
+            "GET /path?query HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Connection: keep-alive, X-Client-Hop\r\n\
+             Keep-Alive: timeout=5\r\n\
+             Proxy-Authorization: secret\r\n\
+             Te: trailers\r\n\
+             Upgrade: websocket\r\n\
+             X-Client-Hop: 1\r\n\
+             X-Client-End: 1",
+        );
+
+        let (response, _) = forward(&proxy, &request, false).await;
+
+        let head = received.lock().unwrap()[0].to_ascii_lowercase();
+        assert!(head.starts_with("get /path?query http/1.1\r\n"));
+        assert!(head.contains(&format!("\r\nhost: {}\r\n", authority)));
+        assert!(head.contains("\r\nconnection: close\r\n"));
+        assert!(head.contains("\r\nx-client-end: 1\r\n"));
+        for name in [
+            "keep-alive",
+            "proxy-authorization",
+            "te",
+            "upgrade",
+            "x-client-hop",
+        ] {
+            assert!(
+                !head.contains(&format!("\r\n{}:", name)),
+                "{} forwarded",
+                name
+            );
+        }
+
+        let headers = response.headers();
+        assert!(headers.contains_key("X-Upstream-End"));
+        for name in ["Connection", "Keep-Alive", "X-Upstream-Hop"] {
+            assert!(!headers.contains_key(name), "{} relayed", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn announces_the_client_in_forwarding_headers() {
+        let (authority, received) = upstream(|_| ok("")).await;
+        let proxy = proxy(&[&authority], None);
+        let request = request(
+            "GET / HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             X-Forwarded-For: 192.0.2.1\r\n\
+             X-Forwarded-Host: forged.test\r\n\
+             Forwarded: for=192.0.2.1",
+        );
+
+        // Chains of untrusted peers are replaced
+        forward(&proxy, &request, false).await;
+        // Chains of trusted proxies are extended
+        forward(&proxy, &request, true).await;
+
+        let heads = received.lock().unwrap();
+        let header = |head: &str, name: &str| {
+            head.split("\r\n")
+                .filter_map(|line| line.strip_prefix(&format!("{}: ", name)))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(header(&heads[0], "X-Forwarded-For"), ["198.51.100.1"]);
+        assert_eq!(header(&heads[0], "X-Forwarded-Proto"), ["https"]);
+        assert_eq!(header(&heads[0], "X-Forwarded-Host"), ["example.com"]);
+        assert_eq!(
+            header(&heads[0], "Forwarded"),
+            ["for=198.51.100.1;proto=https;host=\"example.com\""]
+        );
+
+        assert_eq!(
+            header(&heads[1], "X-Forwarded-For"),
+            ["192.0.2.1, 198.51.100.1"]
+        );
+        assert_eq!(header(&heads[1], "X-Forwarded-Host"), ["example.com"]);
+        assert_eq!(
+            header(&heads[1], "Forwarded"),
+            ["for=192.0.2.1, for=198.51.100.1;proto=https;host=\"example.com\""]
+        );
+    }
+
+    #[test]
+    fn brackets_ipv6_clients_in_forwarded() {
+        assert_eq!(
+            forwarded_node("2001:db8::1".parse().unwrap()),
+            "\"[2001:db8::1]\""
+        );
+        assert_eq!(forwarded_node("192.0.2.1".parse().unwrap()), "192.0.2.1");
+    }
+
+    #[tokio::test]
+    async fn relays_length_framed_bodies_as_they_are() {
+        let (authority, _) = upstream(|_| ok("hello world")).await;
+        let proxy = proxy(&[&authority], None);
+
+        let (response, body) = forward(&proxy, &request("GET / HTTP/1.1\r\nHost: x"), false).await;
+
+        assert_eq!(body, "hello world");
+        assert_eq!(response.headers()["Content-Length"], "11");
+    }
+
+    #[tokio::test]
+    async fn relays_chunked_bodies() {
+        let (authority, _) = upstream(|_| {
+            b"HTTP/1.1 200 OK\r\n\
+              Transfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: dropped\r\n\r\n"
+                .to_vec()
+        })
+        .await;
+        let proxy = proxy(&[&authority], None);
+
+        let (response, body) = forward(&proxy, &request("GET / HTTP/1.1\r\nHost: x"), false).await;
+
+        // Relayed chunked, the length being unknown
+        assert_eq!(body, "hello world");
+        assert!(!response.headers().contains_key("Content-Length"));
+        assert!(!response.headers().contains_key("Transfer-Encoding"));
+    }
+
+    #[tokio::test]
+    async fn relays_bodies_ending_with_the_connection() {
+        let (authority, _) =
+            upstream(|_| b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello world".to_vec()).await;
+        let proxy = proxy(&[&authority], None);
+
+        let (response, body) = forward(&proxy, &request("GET / HTTP/1.1\r\nHost: x"), false).await;
+
+        assert_eq!(body, "hello world");
+        assert!(!response.headers().contains_key("Content-Length"));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_length_of_bodiless_responses() {
+        let (authority, _) = upstream(|head| match head.starts_with("HEAD ") {
+            true => b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n".to_vec(),
+            false => b"HTTP/1.1 304 Not Modified\r\nContent-Length: 11\r\n\r\n".to_vec(),
+        })
+        .await;
+        let proxy = proxy(&[&authority], None);
+
+        for head in ["HEAD / HTTP/1.1\r\nHost: x", "GET / HTTP/1.1\r\nHost: x"] {
+            let (response, body) = forward(&proxy, &request(head), false).await;
+
+            assert_eq!(body, "");
+            assert!(!response.is_streaming());
+            assert!(String::from_utf8(response.as_bytes())
+                .unwrap()
+                .contains("\r\nContent-Length: 11\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_cookies_as_separate_lines() {
+        let (authority, _) = upstream(|_| {
+            b"HTTP/1.1 200 OK\r\n\
+              Set-Cookie: a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT\r\n\
+              Set-Cookie: b=2\r\n\
+              Vary: Accept\r\n\
+              Vary: Origin\r\n\
+              Content-Length: 0\r\n\r\n"
+                .to_vec()
+        })
+        .await;
+        let proxy = proxy(&[&authority], None);
+
+        let (response, _) = forward(&proxy, &request("GET / HTTP/1.1\r\nHost: x"), false).await;
+
+        let cookies = response
+            .header_lines()
+            .filter(|(name, _)| *name == "Set-Cookie")
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            cookies,
+            ["a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT", "b=2"]
+        );
+        assert_eq!(response.headers()["Vary"], "Accept, Origin");
+    }
+}
@@ -0,0 +1,235 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature opening a version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the PROXY protocol header a load balancer sends before anything else on the
+/// connection, versions 1 and 2 alike. Returns the address of the client it accepted the
+/// connection from, or `None` for connections the load balancer opened itself, like its
+/// health checks.
+///
+/// Reads no further than the header, so that the connection can be served as if it had
+/// just been accepted.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // Both versions are longer than this
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(anyhow!("Expected a PROXY protocol header"))
+    }
+}
+
+/// Parses the rest of `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut header = start.to_vec();
+
+    // Read byte by byte, not to consume what follows the header
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            return Err(anyhow!("PROXY protocol header too long"));
+        }
+
+        header.push(stream.read_u8().await?);
+    }
+
+    let header = String::from_utf8(header)?;
+    let invalid = || anyhow!("Invalid PROXY protocol header: {}", header.trim_end());
+
+    let fields = header.trim_end().split(' ').collect::<Vec<_>>();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| invalid())?;
+            let port = port.parse::<u16>().map_err(|_| invalid())?;
+
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid());
+            }
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses the binary header following the signature.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+
+    // Addresses are followed by optional TLVs, which are skipped
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("Unsupported PROXY protocol version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(anyhow!("Unsupported PROXY protocol command")),
+    }
+
+    let too_short = || anyhow!("PROXY protocol addresses too short");
+
+    // The high nibble is the address family, the low one the transport
+    match family >> 4 {
+        // AF_INET: source and destination addresses, then ports
+        1 => {
+            let addresses = payload.get(..12).ok_or_else(too_short)?;
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 => {
+            let addresses = payload.get(..36).ok_or_else(too_short)?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        }
+        // AF_UNSPEC or AF_UNIX, which tell nothing about the client's address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Reads the header at the start of `bytes`, returning the address and what is left.
+    async fn read(bytes: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = bytes;
+        let addr = read_header(&mut stream).await?;
+
+        Ok((addr, stream.to_vec()))
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers_and_nothing_more() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1")
+            .await
+            .unwrap();
+
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reads_v1_unknown_connections_as_local() {
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\nGET").await.unwrap();
+
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v1_headers() {
+        let invalid: [&[u8]; 5] = [
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ];
+
+        for header in invalid {
+            assert!(
+                read(header).await.is_err(),
+                "{}",
+                String::from_utf8_lossy(header)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_v1_headers_without_an_end() {
+        let header = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+
+        assert!(read(header.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers_skipping_tlvs() {
+        let mut payload = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        payload.extend_from_slice(&56324u16.to_be_bytes());
+        payload.extend_from_slice(&443u16.to_be_bytes());
+        // A TLV of the load balancer
+        payload.extend_from_slice(&[0x04, 0, 1, 0]);
+
+        let mut bytes = v2(1, 0x11, &payload);
+        bytes.extend_from_slice(b"GET");
+
+        let (addr, rest) = read(&bytes).await.unwrap();
+
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_ipv6_headers() {
+        let client = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+
+        let mut payload = client.octets().to_vec();
+        payload.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        payload.extend_from_slice(&56324u16.to_be_bytes());
+        payload.extend_from_slice(&443u16.to_be_bytes());
+
+        let (addr, _) = read(&v2(1, 0x21, &payload)).await.unwrap();
+
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reads_v2_local_and_unix_connections_as_local() {
+        assert_eq!(read(&v2(0, 0x11, &[0; 12])).await.unwrap().0, None);
+        assert_eq!(read(&v2(1, 0x31, &[0; 216])).await.unwrap().0, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v2_headers() {
+        // Addresses cut short
+        assert!(read(&v2(1, 0x11, &[192, 0, 2, 1])).await.is_err());
+        // Unknown command
+        assert!(read(&v2(2, 0x11, &[0; 12])).await.is_err());
+
+        // Version 1 in the binary format
+        let mut header = v2(1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(read(&header).await.is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::{
+        fd::RawFd,
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+            process::CommandExt,
+        },
+    },
+    path::PathBuf,
+    process::{self, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use anyhow::{Context, Result};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::listener::{self, LISTEN_FDS_START, PARENT_PID_VAR};
+
+/// Set by systemd in the environment of the processes of a unit.
+const INVOCATION_ID_VAR: &str = "INVOCATION_ID";
+
+/// Set by systemd when the processes of a unit may send it notifications, see sd_notify(3).
+const NOTIFY_SOCKET_VAR: &str = "NOTIFY_SOCKET";
+
+/// Replaces the running binary without dropping connections on SIGUSR2, e.g. after a deploy.
+///
+/// A new process of the binary is started with the same arguments, receiving the listening
+/// sockets like systemd passes them. Once it accepts connections, it sends SIGTERM to this
+/// process, which then drains its connections through the graceful shutdown. Should the new
+/// process fail to start, this one keeps serving.
+///
+/// systemd stops a unit once its main process exits, killing the new process along with it,
+/// so the new process first tells systemd to make it the main process. The unit must allow
+/// it with `NotifyAccess=all`; reloads are refused under systemd without a notify socket.
+pub fn reload_on_sigusr2(fds: Vec<RawFd>) -> Result<()> {
+    let mut user_signal = signal(SignalKind::user_defined2())?;
+    let reloading = Arc::new(AtomicBool::new(false));
+
+    tokio::spawn(async move {
+        while user_signal.recv().await.is_some() {
+            if env::var_os(INVOCATION_ID_VAR).is_some() && env::var_os(NOTIFY_SOCKET_VAR).is_none()
+            {
+                error!("Cannot reload under systemd without NotifyAccess=all in the unit");
+                continue;
+            }
+
+            if reloading.swap(true, Ordering::SeqCst) {
+                warn!("Reload already in progress");
+                continue;
+            }
+
+            let mut child = match spawn_child(&fds) {
+                Ok(child) => child,
+                Err(e) => {
+                    error!("Error starting the new process: {:#}", e);
+                    reloading.store(false, Ordering::SeqCst);
+                    continue;
+                }
+            };
+
+            info!(
+                pid = child.id(),
+                "Reloading, handing the listening sockets over"
+            );
+
+            // The Unix socket files now belong to the new process
+            listener::set_handed_off(true);
+
+            let reloading = reloading.clone();
+
+            // The new process outlives this one when it starts, so waiting only returns
+            // if it failed. A thread of its own does not hold the runtime up on exit
+            thread::spawn(move || {
+                let status = child.wait();
+
+                error!(?status, "The new process exited, reload aborted");
+                listener::set_handed_off(false);
+                reloading.store(false, Ordering::SeqCst);
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn spawn_child(fds: &[RawFd]) -> Result<process::Child> {
+    let mut command = Command::new(current_exe()?);
+
+    command
+        .args(env::args_os().skip(1))
+        .env("LISTEN_FDS", fds.len().to_string())
+        .env(PARENT_PID_VAR, process::id().to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDNAMES");
+
+    let fds = fds.to_vec();
+    let mut moved = vec![0; fds.len()];
+    let first_free = LISTEN_FDS_START + fds.len() as RawFd;
+
+    // Runs in the child between fork and exec, where only async-signal-safe calls are allowed
+    unsafe {
+        command.pre_exec(move || {
+            // Sockets may sit where others must go, so they are all moved out of the way first.
+            // The copies are closed by exec
+            for (fd, moved) in fds.iter().zip(moved.iter_mut()) {
+                *moved = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, first_free);
+
+                if *moved < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            // Descriptors created by dup2 are kept open across exec
+            for (index, fd) in moved.iter().enumerate() {
+                if libc::dup2(*fd, LISTEN_FDS_START + index as RawFd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+    command.spawn().context("Cannot start the new process")
+}
+
+/// The path of the binary, which Linux suffixes with " (deleted)" once a deploy replaced it.
+fn current_exe() -> Result<PathBuf> {
+    let exe = env::current_exe().context("Cannot find the path of the binary")?;
+
+    match exe.to_str().and_then(|exe| exe.strip_suffix(" (deleted)")) {
+        Some(exe) => Ok(PathBuf::from(exe)),
+        None => Ok(exe),
+    }
+}
+
+/// Tells the process that handed its sockets over that this one accepts connections,
+/// so that it shuts down.
+///
+/// Should it have exited already, this process was reparented, and whatever adopted it, like
+/// init or a subreaper, must not be stopped instead.
+///
+/// Fails if systemd could not be told that this process is the main one now, in which case
+/// the previous process is left running, as stopping it would stop the unit.
+pub fn notify_parent(parent: u32) -> Result<()> {
+    if std::os::unix::process::parent_id() != parent {
+        warn!(parent, "The previous process already exited");
+        return Ok(());
+    }
+
+    if let Some(notify_socket) = env::var_os(NOTIFY_SOCKET_VAR) {
+        notify_systemd(&notify_socket, &format!("MAINPID={}", process::id()))
+            .context("Cannot become the main process of the systemd unit")?;
+    }
+
+    info!(parent, "Taking over from the previous process");
+
+    // The parent drains its connections on SIGTERM
+    if unsafe { libc::kill(parent as libc::pid_t, libc::SIGTERM) } != 0 {
+        error!(
+            parent,
+            "Error stopping the previous process: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
+
+/// Sends a notification to systemd, as sd_notify(3) does. Names starting with `@` are in the
+/// abstract namespace.
+fn notify_systemd(notify_socket: &OsStr, state: &str) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    match notify_socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => {
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?
+        }
+        None => socket.send_to(state.as_bytes(), notify_socket)?,
+    };
+
+    Ok(())
+}
@@ -1,9 +1,23 @@
-use std::{collections::HashMap, fmt::Display, string::FromUtf8Error};
+use std::{
+    collections::{hash_map, HashMap},
+    fmt::Display,
+    iter,
+    string::FromUtf8Error,
+};
+
+use itertools::Itertools;
+use tokio::time::error::Elapsed;
 
 #[derive(Debug)]
 pub enum HTTPError {
     IoError(std::io::Error),
     IllegalMethod,
+    /// The client did not send the request within the configured timeout.
+    Timeout,
+    /// The request line and headers are larger than allowed.
+    HeadTooLarge,
+    /// The body announced is larger than allowed.
+    BodyTooLarge,
     Other(String),
 }
 
@@ -13,6 +27,12 @@ impl From<std::io::Error> for HTTPError {
     }
 }
 
+impl From<Elapsed> for HTTPError {
+    fn from(_: Elapsed) -> Self {
+        HTTPError::Timeout
+    }
+}
+
 impl From<FromUtf8Error> for HTTPError {
     fn from(value: FromUtf8Error) -> Self {
         HTTPError::Other(value.to_string())
@@ -57,6 +77,14 @@ pub struct RequestLine {
 }
 
 impl RequestLine {
+    pub fn new(method: HTTPMethod, path: &str, version: &str) -> RequestLine {
+        RequestLine {
+            method,
+            path: path.to_string(),
+            version: version.to_string(),
+        }
+    }
+
     fn parse_request_line(request_line: &str) -> Result<RequestLine, HTTPError> {
         let parts: Vec<&str> = request_line.split_whitespace().collect();
 
@@ -88,12 +116,74 @@ impl RequestLine {
     }
 }
 
+/// Request headers, looked up whatever the casing of their names, while handlers iterating
+/// over them see the names as the client sent them.
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    /// Names as sent and values, by canonical name.
+    by_name: HashMap<String, (String, String)>,
+}
+
+type HeadersIter<'a> = iter::Map<
+    hash_map::Values<'a, String, (String, String)>,
+    fn(&'a (String, String)) -> (&'a String, &'a String),
+>;
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.get_key_value(name).map(|(_, value)| value)
+    }
+
+    /// The header with its name as sent.
+    pub fn get_key_value(&self, name: &str) -> Option<(&String, &String)> {
+        self.by_name
+            .get(&canonical_header_name(name))
+            .map(|(name, value)| (name, value))
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.by_name.contains_key(&canonical_header_name(name))
+    }
+
+    /// Sets a header, replacing any with the same name in another casing.
+    pub fn insert(&mut self, name: String, value: String) -> Option<String> {
+        self.by_name
+            .insert(canonical_header_name(&name), (name, value))
+            .map(|(_, value)| value)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.by_name
+            .remove(&canonical_header_name(name))
+            .map(|(_, value)| value)
+    }
+
+    /// The headers with their names as sent, in no particular order.
+    pub fn iter(&self) -> HeadersIter<'_> {
+        self.by_name.values().map(|(name, value)| (name, value))
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a String, &'a String);
+    type IntoIter = HeadersIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Request {
     request_line: RequestLine,
-    headers: HashMap<String, String>,
+    headers: Headers,
     body: Option<Vec<u8>>,
     params: HashMap<String, String>,
+    request_id: Option<String>,
 }
 
 impl Display for Request {
@@ -105,61 +195,106 @@ impl Display for Request {
             self.request_line.path,
             self.request_line.version,
             self.headers,
-            String::from_utf8(self.body.as_ref().unwrap_or(&Vec::new()).to_vec()).unwrap(),
+            String::from_utf8_lossy(self.body.as_deref().unwrap_or_default()),
             self.params,
         )
     }
 }
 
 impl Request {
-    pub fn parse_request(request: &str) -> Result<Request, HTTPError> {
-        let parts = request.split("\r\n\r\n").collect::<Vec<&str>>();
-
-        let headers = parts.first();
-        let body = parts.get(1);
+    pub fn new(request_line: RequestLine, headers: Headers, body: Vec<u8>) -> Request {
+        Request {
+            request_line,
+            headers,
+            body: Request::parse_body(body),
+            params: HashMap::new(),
+            request_id: None,
+        }
+    }
 
+    pub fn parse_request(request: &[u8]) -> Result<Request, HTTPError> {
         // If we don't have both headers and body by splitting on \r\n\r\n
         // the request is malformed
-        let (headers, body) = match (headers, body) {
-            (Some(headers), Some(body)) => (*headers, *body),
-            _ => {
-                return Err(HTTPError::Other(
-                    "Invalid request: malformed HTTP".to_string(),
-                ))
-            }
-        };
+        let head_end = Request::find_head_end(request).ok_or(HTTPError::Other(
+            "Invalid request: malformed HTTP".to_string(),
+        ))?;
+
+        let head = String::from_utf8(request[..head_end].to_vec())?;
+
+        let mut request_obj = Request::parse_head(&head)?;
+        request_obj.set_body(request[head_end..].to_vec());
+
+        Ok(request_obj)
+    }
 
-        let mut headers = headers.split("\r\n").collect::<Vec<&str>>();
+    /// Parses the request line and headers, leaving the body empty.
+    /// The head may or may not include the trailing `\r\n\r\n`.
+    pub fn parse_head(head: &str) -> Result<Request, HTTPError> {
+        let head = head.trim_end_matches("\r\n");
+
+        let mut headers = head.split("\r\n").collect::<Vec<&str>>();
         // The first line is the request line
         let request_line = headers.remove(0);
 
         let request_line = RequestLine::parse_request_line(request_line)?;
-        let headers = Request::parse_headers(headers);
-        let body = Request::parse_body(body);
+        let headers = Request::parse_headers(headers)?;
 
         Ok(Request {
             request_line,
             headers,
-            body,
+            body: None,
             params: HashMap::new(),
+            request_id: None,
         })
     }
 
-    fn parse_headers(headers: Vec<&str>) -> HashMap<String, String> {
-        let mut headers_map = HashMap::new();
+    /// Returns the length of the request head including the blank line that terminates it,
+    /// or `None` if the head is not complete yet.
+    pub fn find_head_end(buffer: &[u8]) -> Option<usize> {
+        buffer
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|position| position + 4)
+    }
+
+    fn parse_headers(headers: Vec<&str>) -> Result<Headers, HTTPError> {
+        let mut headers_map = Headers::new();
 
         for line in headers {
-            let parts = line.split(": ").collect::<Vec<&str>>();
-            headers_map.insert(parts[0].to_string(), parts[1].to_string());
+            let (key, value) = line
+                .split_once(':')
+                .ok_or(HTTPError::Other(format!("Invalid header: {}", line)))?;
+            let key = key.trim();
+
+            let value = value.trim();
+
+            // Proxies and virtual hosts could each pick a different one
+            if key.eq_ignore_ascii_case("Host") && headers_map.contains_key("Host") {
+                return Err(HTTPError::Other("Several Host headers".to_string()));
+            }
+
+            // A proxy in front could frame the body by the other one, desynchronizing the
+            // requests that follow on the connection
+            if key.eq_ignore_ascii_case("Content-Length")
+                && headers_map
+                    .get("Content-Length")
+                    .is_some_and(|length| length != value)
+            {
+                return Err(HTTPError::Other(
+                    "Conflicting Content-Length headers".to_string(),
+                ));
+            }
+
+            headers_map.insert(key.to_string(), value.to_string());
         }
 
-        headers_map
+        Ok(headers_map)
     }
 
-    fn parse_body(body: &str) -> Option<Vec<u8>> {
-        match body {
-            "" => None,
-            _ => Some(body.as_bytes().to_vec()),
+    fn parse_body(body: Vec<u8>) -> Option<Vec<u8>> {
+        match body.is_empty() {
+            true => None,
+            false => Some(body),
         }
     }
 
@@ -167,7 +302,7 @@ impl Request {
         &self.request_line
     }
 
-    pub fn headers(&self) -> &HashMap<String, String> {
+    pub fn headers(&self) -> &Headers {
         &self.headers
     }
 
@@ -175,6 +310,33 @@ impl Request {
         self.body.as_ref()
     }
 
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = Request::parse_body(body);
+    }
+
+    /// Returns the value of the `Content-Length` header, or 0 if the request has none.
+    pub fn content_length(&self) -> Result<usize, HTTPError> {
+        self.headers
+            .get("Content-Length")
+            .map(|len| len.parse::<usize>())
+            .unwrap_or(Ok(0))
+            .map_err(|_| HTTPError::Other("Invalid Content-Length".to_string()))
+    }
+
+    /// Checks whether a comma-separated header, like `Connection`, contains a token.
+    /// Tokens are compared case-insensitively.
+    pub fn has_header_token(&self, header: &str, token: &str) -> bool {
+        self.headers.get(header).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|x| x.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
     pub fn params(&self) -> &HashMap<String, String> {
         &self.params
     }
@@ -187,7 +349,75 @@ impl Request {
         self.params.extend(params);
     }
 
+    /// The ID correlating this request with its logs, set once the server starts handling it.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub fn set_request_id(&mut self, request_id: String) {
+        self.request_id = Some(request_id);
+    }
+
     pub fn method(&self) -> &HTTPMethod {
         self.request_line.method()
     }
 }
+
+/// Normalizes a header name to its canonical form (e.g. `content-type` to `Content-Type`),
+/// so that header lookups do not depend on the casing used by the client. Only lookups use
+/// it: requests keep the names as sent.
+pub fn canonical_header_name(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+
+            match chars.next() {
+                Some(first) => {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                }
+                None => String::new(),
+            }
+        })
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn parse(headers: &[&str]) -> Result<Request, HTTPError> {
+        let head = ["GET / HTTP/1.1"]
+            .iter()
+            .chain(headers)
+            .map(|line| format!("{}\r\n", line))
+            .collect::<String>();
+
+        Request::parse_head(&head)
+    }
+
+    #[test]
+    fn rejects_several_host_headers() {
+        assert!(parse(&["Host: a.example.com", "Host: b.example.com"]).is_err());
+        assert!(parse(&["Host: example.com", "host: example.com"]).is_err());
+    }
+
+    #[test]
+    fn accepts_a_single_host_header() {
+        let request = parse(&["host: example.com", "Accept: */*"]).unwrap();
+
+        assert_eq!(
+            request.headers().get_key_value("Host"),
+            Some((&"host".to_string(), &"example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_content_lengths() {
+        assert!(parse(&["Content-Length: 3", "content-length: 4"]).is_err());
+
+        let request = parse(&["Content-Length: 3", "Content-Length: 3"]).unwrap();
+        assert_eq!(request.content_length().unwrap(), 3);
+    }
+}
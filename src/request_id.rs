@@ -0,0 +1,53 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use crate::request::Request;
+
+/// The header a request ID is read from and echoed in.
+pub const HEADER: &str = "X-Request-Id";
+
+const MAX_LEN: usize = 200;
+
+/// Gives the request the ID sent by the client, or a new one if it sent none.
+/// Requests that already have an ID keep it.
+///
+/// Client IDs end up in logs, so only short IDs made of safe characters are accepted.
+pub fn assign(request: &mut Request) {
+    if request.request_id().is_some() {
+        return;
+    }
+
+    let id = match request.headers().get(HEADER) {
+        Some(id) if is_valid(id) => id.clone(),
+        _ => generate(),
+    };
+
+    request.set_request_id(id);
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:/+=@".contains(c))
+}
+
+/// Generates an ID unique to this process, made of a random prefix chosen at startup
+/// and a counter, as 32 hex digits.
+pub fn generate() -> String {
+    static PREFIX: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // The standard library seeds RandomState from the OS random number generator
+    let prefix = PREFIX.get_or_init(|| RandomState::new().build_hasher().finish());
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:016x}{:016x}", prefix, count)
+}
@@ -1,5 +1,9 @@
 use anyhow::{anyhow, Result};
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, future::Future, pin::Pin};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
 
 #[derive(Debug)]
 struct Status {
@@ -21,10 +25,27 @@ impl Status {
         }
     }
 }
+/// A connection handed over to a response's upgrade callback.
+pub type Upgraded = Box<dyn UpgradedIo>;
+
+pub trait UpgradedIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UpgradedIo for T {}
+
+pub type UpgradeFn =
+    Box<dyn FnOnce(Upgraded) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send>;
+
+/// Chunks of a response body produced while the response is being sent.
+pub type BodyStream = mpsc::Receiver<Vec<u8>>;
+
 pub struct ResponseBuilder {
     status: Option<Status>,
     headers: HashMap<String, String>,
+    /// Headers sent as lines of their own, next to `headers`.
+    appended_headers: Vec<(String, String)>,
     body: Vec<u8>,
+    body_stream: Option<BodyStream>,
+    upgrade: Option<UpgradeFn>,
 }
 
 impl Default for ResponseBuilder {
@@ -38,7 +59,10 @@ impl ResponseBuilder {
         ResponseBuilder {
             status: None,
             headers: HashMap::new(),
+            appended_headers: Vec::new(),
             body: Vec::new(),
+            body_stream: None,
+            upgrade: None,
         }
     }
 
@@ -53,6 +77,14 @@ impl ResponseBuilder {
         self
     }
 
+    /// Adds a header line without replacing the lines already sent under that name, for
+    /// headers whose values cannot be combined into one line, like `Set-Cookie`.
+    pub fn append_header(mut self, key: &str, value: &str) -> Self {
+        self.appended_headers
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
     pub fn headers(mut self, headers: &[(&str, &str)]) -> Self {
         let mut headers_map = HashMap::new();
 
@@ -69,16 +101,46 @@ impl ResponseBuilder {
         self
     }
 
+    pub fn get_status(&self) -> Option<u16> {
+        self.status.as_ref().map(|status| status.code)
+    }
+
     pub fn get_body(&self) -> &Vec<u8> {
         &self.body
     }
 
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+
+    /// Streams the body from a channel instead of a buffer; the body ends when every sender
+    /// is dropped. Unless a `Content-Length` header announces the length upfront, HTTP/1.1
+    /// uses chunked encoding.
+    pub fn body_stream(mut self, body_stream: BodyStream) -> Self {
+        self.body_stream = Some(body_stream);
+        self
+    }
+
+    /// Sets the callback that takes over the connection once this response is written.
+    /// Only honored for `101 Switching Protocols` responses over HTTP/1.1.
+    pub fn on_upgrade<F, Fut>(mut self, upgrade: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(move |upgraded| Box::pin(upgrade(upgraded))));
+        self
+    }
+
     pub fn build(self) -> Result<Response> {
         if let Some(status) = self.status {
             Ok(Response {
                 status,
                 headers: self.headers,
+                appended_headers: self.appended_headers,
                 body: self.body,
+                body_stream: self.body_stream,
+                upgrade: self.upgrade,
             })
         } else {
             Err(anyhow!("Cannot build Response without a status"))
@@ -86,22 +148,95 @@ impl ResponseBuilder {
     }
 }
 
-#[derive(Debug)]
 pub struct Response {
     status: Status,
     headers: HashMap<String, String>,
+    appended_headers: Vec<(String, String)>,
     body: Vec<u8>,
+    body_stream: Option<BodyStream>,
+    upgrade: Option<UpgradeFn>,
+}
+
+impl std::fmt::Debug for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("appended_headers", &self.appended_headers)
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Response {
+    pub fn status_code(&self) -> u16 {
+        self.status.code
+    }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    /// Every header line to send, including the repeated ones added with
+    /// `ResponseBuilder::append_header`.
+    pub fn header_lines(&self) -> impl Iterator<Item = (&str, &str)> {
+        let appended = self
+            .appended_headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()));
+
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(appended)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.body_stream.is_some()
+    }
+
+    /// Whether the body is streamed with chunked encoding over HTTP/1.1, rather than as
+    /// many bytes as its `Content-Length` announces.
+    pub fn is_chunked(&self) -> bool {
+        self.is_streaming() && !self.headers.contains_key("Content-Length")
+    }
+
+    pub fn take_body_stream(&mut self) -> Option<BodyStream> {
+        self.body_stream.take()
+    }
+
+    pub fn take_upgrade(&mut self) -> Option<UpgradeFn> {
+        match self.status.code {
+            101 => self.upgrade.take(),
+            _ => None,
+        }
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut response = format!("HTTP/1.1 {}\r\n", self.status);
 
-        for (key, value) in &self.headers {
+        for (key, value) in self.header_lines() {
             response.push_str(&format!("{}: {}\r\n", key, value));
         }
 
-        response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        // Informational and 204 responses must not carry a Content-Length. One set by the
+        // handler is kept, e.g. for responses to HEAD requests, which have no body.
+        if self.is_chunked() {
+            response.push_str("Transfer-Encoding: chunked\r\n");
+        } else if !self.headers.contains_key("Content-Length")
+            && self.status.code >= 200
+            && self.status.code != 204
+        {
+            response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
 
         response.push_str("\r\n");
 
@@ -1,18 +1,44 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    os::fd::AsRawFd,
+    sync::Arc,
+    time::Duration,
+};
 
 use itertools::Itertools;
 use regex::Regex;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    task::{self, JoinSet},
+    time::{sleep, timeout, timeout_at, Instant},
 };
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 
 use crate::{
-    config::Config,
+    access_log::{AccessLog, RequestLog},
+    config::{Config, RequestLimits, Timeouts},
+    forwarded::{Client, TrustedProxies},
+    health::{self, Health},
+    http2,
+    limits::{ConnectionLimiter, ConnectionStats, RETRY_AFTER},
+    listener::{self, Connection, ListenAddr, ListenSocket},
+    metrics::{self, Metrics},
+    middleware::Middleware,
+    proxy_protocol, reload,
     request::{HTTPError, HTTPMethod, Request},
-    response::ResponseBuilder,
+    request_id,
+    response::{BodyStream, ResponseBuilder},
+    shutdown::{Shutdown, ShutdownHandle},
+    tls::Tls,
+    utils::Rewind,
+    vhost::{VirtualHost, VirtualHosts},
 };
 
 pub struct Route {
@@ -51,34 +77,94 @@ impl Route {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RequestInfo {
     request: Request,
+    peer_addr: SocketAddr,
     server_info: Info,
+    host: Arc<VirtualHost>,
+    client: Client,
+    route: Option<String>,
 }
 
 impl RequestInfo {
-    fn new(request: Request, server_info: Info) -> Self {
+    fn new(
+        request: Request,
+        peer_addr: SocketAddr,
+        server_info: Info,
+        host: Arc<VirtualHost>,
+        client: Client,
+    ) -> Self {
         Self {
             request,
+            peer_addr,
             server_info,
+            host,
+            client,
+            route: None,
         }
     }
 
+    /// The path of the route matching the request, e.g. `/files/:filename`. Requests are
+    /// matched before the middlewares run, so they see it too.
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    /// The address the request was received from, a proxy's if there is one in front.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// The address of the client, as told by trusted proxies, or the peer address.
+    pub fn client_ip(&self) -> IpAddr {
+        self.client.ip
+    }
+
+    /// The scheme the client used, `http` or `https`.
+    pub fn scheme(&self) -> &'static str {
+        self.client.scheme
+    }
+
+    /// The host the client requested, from `Host` unless trusted proxies tell otherwise.
+    pub fn requested_host(&self) -> Option<&str> {
+        self.client.host.as_deref()
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
     pub fn request(&self) -> &Request {
         &self.request
     }
 
+    pub fn request_mut(&mut self) -> &mut Request {
+        &mut self.request
+    }
+
+    /// The public directory of the virtual host serving the request.
     pub fn pub_dir(&self) -> &str {
-        self.server_info.pub_dir()
+        self.host.pub_dir()
+    }
+
+    pub fn server_info(&self) -> &Info {
+        &self.server_info
+    }
+
+    /// The virtual host serving the request, selected by its `Host` header.
+    pub fn host(&self) -> &VirtualHost {
+        &self.host
     }
 }
 
-type RouteHandlerFn = fn(RequestInfo) -> Result<ResponseBuilder>;
+pub type RouteHandlerFn = fn(RequestInfo) -> Result<ResponseBuilder>;
 #[derive(Debug, Clone)]
 pub struct RouteHandler {
     handler_fn: RouteHandlerFn,
     method: HTTPMethod,
+    /// The path the handler was registered with, e.g. `/files/:filename`.
+    path: String,
     pattern: Regex,
     params: Vec<String>,
 }
@@ -87,17 +173,23 @@ impl RouteHandler {
     pub fn new(
         handler: RouteHandlerFn,
         method: HTTPMethod,
+        path: &str,
         pattern: Regex,
         params: &[String],
     ) -> Self {
         RouteHandler {
             handler_fn: handler,
             method,
+            path: path.to_string(),
             pattern,
             params: params.to_vec(),
         }
     }
 
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     pub fn handler_fn(&self) -> RouteHandlerFn {
         self.handler_fn
     }
@@ -105,115 +197,582 @@ impl RouteHandler {
     pub fn pattern(&self) -> &Regex {
         &self.pattern
     }
+
+    /// Matches a request path, leaving its query string out, and returns the params it
+    /// captures, or `None` if the path does not match.
+    pub fn match_path(&self, path: &str) -> Option<HashMap<String, String>> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let captures = self.pattern.captures(path)?;
+
+        let params = self
+            .params
+            .iter()
+            .zip(captures.iter().skip(1))
+            .map(|(name, value)| {
+                let value = value.map_or("", |value| value.as_str());
+                (name.to_string(), value.replace("%20", " "))
+            })
+            .collect();
+
+        Some(params)
+    }
 }
 
-type RouteHandlers = Vec<RouteHandler>;
+type Middlewares = Vec<Arc<dyn Middleware>>;
 
 #[derive(Debug, Clone)]
 pub struct Info {
     pub_dir: String,
+    connection_stats: Arc<ConnectionStats>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    trusted_proxies: Arc<TrustedProxies>,
 }
 
 impl Info {
+    /// The public directory of the default host.
     pub fn pub_dir(&self) -> &str {
         &self.pub_dir
     }
+
+    pub fn connection_stats(&self) -> &ConnectionStats {
+        &self.connection_stats
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn trusted_proxies(&self) -> &TrustedProxies {
+        &self.trusted_proxies
+    }
+}
+
+/// How connections accepted by a listener negotiate TLS.
+#[derive(Clone)]
+enum TlsMode {
+    Off,
+    Required(Arc<Tls>),
+    /// TLS and plain HTTP share the port, told apart by the first byte the client sends.
+    Detect(Arc<Tls>),
+}
+
+/// How long accepting pauses after a transient error, like running out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+struct Listener {
+    socket: ListenSocket,
+    tls: TlsMode,
+    /// Whether connections start with a PROXY protocol header.
+    proxy_protocol: bool,
+}
+
+impl Listener {
+    /// Accepts connections until the server shuts down. Every connection task holds a clone
+    /// of `drain`, so that the server can wait for all of them to finish.
+    async fn accept_loop(
+        self,
+        router: Router,
+        limiter: Arc<ConnectionLimiter>,
+        mut shutdown: Shutdown,
+        drain: mpsc::Sender<()>,
+    ) -> Result<()> {
+        loop {
+            // Under the stop-accepting policy, nothing is accepted until a slot is free
+            let reserved = tokio::select! {
+                reserved = limiter.reserve() => reserved,
+                _ = shutdown.wait() => return Ok(()),
+            };
+
+            let accepted = tokio::select! {
+                accepted = self.socket.accept() => accepted,
+                _ = shutdown.wait() => return Ok(()),
+            };
+
+            let (mut stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if listener::is_transient_accept_error(&e) => {
+                    warn!("Failed to accept a connection: {}", e);
+
+                    // Running out of file descriptors would fail every attempt until some
+                    // connections close, so give them time instead of spinning
+                    tokio::select! {
+                        _ = sleep(ACCEPT_ERROR_DELAY) => continue,
+                        _ = shutdown.wait() => return Ok(()),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let tls = self.tls.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let router = router.clone();
+            let limiter = limiter.clone();
+            let mut shutdown = shutdown.clone();
+            let drain = drain.clone();
+
+            tokio::spawn(async move {
+                // Unix socket clients have no IP to be limited by
+                let ip = matches!(stream, Connection::Tcp(_)).then_some(peer_addr.ip());
+
+                // Connections still queued when the shutdown starts are rejected
+                let guard = tokio::select! {
+                    guard = limiter.admit(ip, reserved) => guard,
+                    _ = shutdown.wait() => None,
+                };
+
+                let result = match guard {
+                    Some(guard) => {
+                        let result = async {
+                            let client_addr = match proxy_protocol {
+                                true => Self::proxied_peer(&mut stream, peer_addr, &router).await?,
+                                false => peer_addr,
+                            };
+
+                            Self::serve_connection(stream, client_addr, tls, router, shutdown).await
+                        }
+                        .await;
+                        drop(guard);
+                        result
+                    }
+                    None => Self::reject(stream, tls, router).await,
+                };
+
+                if let Err(e) = result {
+                    debug!(%peer_addr, "Connection error: {:#}", e);
+                }
+
+                drop(drain);
+            });
+        }
+    }
+
+    /// Turns a connection away with a 503 response. TLS connections are closed without one,
+    /// as completing a handshake would cost more than the limit is meant to save.
+    async fn reject(connection: Connection, tls: TlsMode, router: Router) -> Result<()> {
+        let mut stream: Box<dyn AsyncWrite + Unpin + Send> = match (connection, tls) {
+            (Connection::Tcp(stream), TlsMode::Off) => Box::new(stream),
+            (Connection::Unix(stream), _) => Box::new(stream),
+            _ => return Ok(()),
+        };
+
+        let response = ResponseBuilder::new()
+            .status(503, "Service Unavailable")
+            .header("Retry-After", &RETRY_AFTER.as_secs().to_string())
+            .header("Connection", "close")
+            .build()?;
+
+        timeout(
+            router.timeouts().write,
+            stream.write_all(&response.as_bytes()),
+        )
+        .await
+        .context("Write timed out")??;
+
+        Ok(())
+    }
+
+    /// Reads the address of the client a load balancer accepted the connection from, sent
+    /// before anything else with the PROXY protocol. Only trusted proxies may send it.
+    async fn proxied_peer(
+        connection: &mut Connection,
+        peer_addr: SocketAddr,
+        router: &Router,
+    ) -> Result<SocketAddr> {
+        if !router.info.trusted_proxies.is_trusted(peer_addr.ip()) {
+            return Err(anyhow!("PROXY protocol header from an untrusted peer"));
+        }
+
+        let header = async {
+            match connection {
+                Connection::Tcp(stream) => proxy_protocol::read_header(stream).await,
+                Connection::Unix(stream) => proxy_protocol::read_header(stream).await,
+            }
+        };
+
+        let client_addr = timeout(router.timeouts().header_read, header)
+            .await
+            .context("PROXY protocol header timed out")??;
+
+        // Connections of the load balancer itself, like health checks, carry no client
+        Ok(client_addr.unwrap_or(peer_addr))
+    }
+
+    async fn serve_connection(
+        connection: Connection,
+        peer_addr: SocketAddr,
+        tls: TlsMode,
+        router: Router,
+        shutdown: Shutdown,
+    ) -> Result<()> {
+        let stream = match connection {
+            Connection::Tcp(stream) => stream,
+            // Unix sockets are local, so never encrypted
+            Connection::Unix(stream) => {
+                return Handler::new(stream, peer_addr, router, shutdown)
+                    .handle()
+                    .await
+            }
+        };
+
+        let timeouts = router.timeouts();
+
+        let tls = match tls {
+            TlsMode::Off => None,
+            TlsMode::Required(tls) => Some(tls),
+            TlsMode::Detect(tls) => {
+                // A TLS connection starts with a handshake record (content type 0x16)
+                let mut first_byte = [0; 1];
+                timeout(timeouts.idle, stream.peek(&mut first_byte))
+                    .await
+                    .context("Connection idle")??;
+
+                (first_byte[0] == 0x16).then_some(tls)
+            }
+        };
+
+        match tls {
+            Some(tls) => {
+                let stream = timeout(timeouts.header_read, tls.acceptor().accept(stream))
+                    .await
+                    .context("TLS handshake timed out")??;
+                Self::serve_tls(stream, peer_addr, router.with_tls(), shutdown).await
+            }
+            None => {
+                Handler::new(stream, peer_addr, router, shutdown)
+                    .handle()
+                    .await
+            }
+        }
+    }
+
+    async fn serve_tls(
+        stream: TlsStream<TcpStream>,
+        peer_addr: SocketAddr,
+        router: Router,
+        shutdown: Shutdown,
+    ) -> Result<()> {
+        // HTTP/2 over TLS is negotiated through ALPN during the handshake
+        if stream.get_ref().1.alpn_protocol() == Some(http2::ALPN_PROTOCOL) {
+            http2::serve(stream, peer_addr, router, shutdown).await
+        } else {
+            Handler::new(stream, peer_addr, router, shutdown)
+                .handle()
+                .await
+        }
+    }
 }
 
-#[derive(Debug)]
 pub struct Server {
-    listener: TcpListener,
-    route_handlers: RouteHandlers,
+    listeners: Vec<Listener>,
+    tls: Option<Arc<Tls>>,
+    shutdown_handle: ShutdownHandle,
+    shutdown_timeout: Duration,
+    pre_stop_delay: Duration,
+    timeouts: Timeouts,
+    request_limits: RequestLimits,
+    limiter: Arc<ConnectionLimiter>,
+    access_log: Arc<AccessLog>,
+    /// Serves requests for hosts without a virtual host of their own.
+    default_host: VirtualHost,
+    hosts: Vec<VirtualHost>,
+    /// Health and metrics routes, served on every host.
+    server_routes: Vec<(String, RouteHandlerFn)>,
+    middlewares: Middlewares,
     info: Info,
 }
 
 impl Server {
-    pub async fn new(socket_addr: SocketAddr, config: Config) -> Result<Server> {
-        let listener = TcpListener::bind(socket_addr).await?;
+    /// Creates a server listening on every address, serving the same routes on all of them.
+    pub async fn new(listen_addrs: &[ListenAddr], config: Config) -> Result<Server> {
+        let tls = config.tls.map(Tls::new).transpose()?.map(Arc::new);
+
+        let mut addrs = Vec::new();
+
+        for listen_addr in listen_addrs.iter().unique() {
+            let socket_addr = match listen_addr {
+                ListenAddr::Tcp(socket_addr) => *socket_addr,
+                ListenAddr::Unix { .. } => {
+                    addrs.push((listen_addr.clone(), TlsMode::Off));
+                    continue;
+                }
+            };
+
+            match &tls {
+                None => addrs.push((listen_addr.clone(), TlsMode::Off)),
+                // Serve HTTPS on its own port when one is configured
+                Some(tls) if tls.port().is_some_and(|port| port != socket_addr.port()) => {
+                    let mut tls_addr = socket_addr;
+                    tls_addr.set_port(tls.port().unwrap());
+
+                    addrs.push((listen_addr.clone(), TlsMode::Off));
+                    addrs.push((ListenAddr::Tcp(tls_addr), TlsMode::Required(tls.clone())));
+                }
+                Some(tls) => addrs.push((listen_addr.clone(), TlsMode::Detect(tls.clone()))),
+            }
+        }
+
+        let mut listeners = Vec::new();
+
+        // Sockets passed by a supervisor are used as they are, the TLS port requiring TLS
+        // and other ports detecting it unless a separate TLS port is configured
+        for socket in ListenSocket::inherited()? {
+            let local_addr = socket.local_addr()?;
+
+            let tls = match (&tls, &local_addr) {
+                (Some(tls), ListenAddr::Tcp(addr)) => match tls.port() {
+                    Some(port) if port == addr.port() => TlsMode::Required(tls.clone()),
+                    Some(_) => TlsMode::Off,
+                    None => TlsMode::Detect(tls.clone()),
+                },
+                _ => TlsMode::Off,
+            };
+
+            // The supervisor already bound this address
+            addrs.retain(|(addr, _)| addr.to_string() != local_addr.to_string());
+
+            listeners.push(Listener {
+                socket,
+                tls,
+                proxy_protocol: config.proxy_protocol,
+            });
+        }
+
+        // A dual-stack listener would take the port of the IPv4 listeners on the same port,
+        // but is kept dual-stack on other ports
+        let inherited_addrs = listeners
+            .iter()
+            .map(|listener| listener.socket.local_addr())
+            .collect::<Result<Vec<_>>>()?;
+        let ipv4_ports = addrs
+            .iter()
+            .map(|(addr, _)| addr)
+            .chain(&inherited_addrs)
+            .filter_map(|addr| match addr {
+                ListenAddr::Tcp(addr) if addr.is_ipv4() => Some(addr.port()),
+                _ => None,
+            })
+            .collect_vec();
+
+        for (addr, tls) in addrs {
+            let only_v6 =
+                matches!(&addr, ListenAddr::Tcp(addr) if ipv4_ports.contains(&addr.port()));
+
+            listeners.push(Listener {
+                socket: ListenSocket::bind(&addr, only_v6)?,
+                tls,
+                proxy_protocol: config.proxy_protocol,
+            });
+        }
+
+        let limiter = Arc::new(ConnectionLimiter::new(config.connection_limits));
+
+        let shutdown_handle = ShutdownHandle::new();
+
+        let mut server_routes: Vec<(String, RouteHandlerFn)> = Vec::new();
+
+        if let Some(metrics_path) = &config.metrics_path {
+            server_routes.push((format!("GET {}", metrics_path), metrics::handle_metrics));
+        }
+
+        if config.health_routes {
+            server_routes.push((
+                format!("GET {}", health::HEALTHZ_PATH),
+                health::handle_healthz,
+            ));
+            server_routes.push((
+                format!("GET {}", health::READYZ_PATH),
+                health::handle_readyz,
+            ));
+        }
+
+        let mut default_host = VirtualHost::new(&[], &config.pub_dir)?;
+        Self::add_server_routes(&mut default_host, &server_routes)?;
+        default_host.add_routes(&config.routes)?;
+
+        let mut hosts = Vec::new();
+
+        for host_config in &config.hosts {
+            let names = host_config.names.iter().map(String::as_str).collect_vec();
+            let pub_dir = host_config.pub_dir.as_deref().unwrap_or(&config.pub_dir);
+
+            let mut host = VirtualHost::new(&names, pub_dir)?;
+            Self::add_server_routes(&mut host, &server_routes)?;
+            host.add_routes(&host_config.routes)?;
+
+            hosts.push(host);
+        }
+
         let info = Info {
             pub_dir: config.pub_dir,
+            connection_stats: limiter.stats(),
+            metrics: Arc::new(Metrics::new()),
+            health: Arc::new(Health::new(shutdown_handle.subscribe())),
+            trusted_proxies: Arc::new(TrustedProxies::new(config.trusted_proxies)),
         };
 
         Ok(Server {
-            listener,
+            listeners,
+            tls,
+            shutdown_handle,
+            shutdown_timeout: config.shutdown_timeout,
+            pre_stop_delay: config.pre_stop_delay,
+            timeouts: config.timeouts,
+            request_limits: config.request_limits,
+            limiter,
+            access_log: Arc::new(AccessLog::new(config.access_log)?),
             info,
-            route_handlers: Vec::new(),
+            default_host,
+            hosts,
+            server_routes,
+            middlewares: Vec::new(),
         })
     }
 
+    /// Registers the routes of the server itself, so that probes and scrapers get the same
+    /// answer whatever host they ask for.
+    fn add_server_routes(
+        host: &mut VirtualHost,
+        server_routes: &[(String, RouteHandlerFn)],
+    ) -> Result<()> {
+        for (path, handler) in server_routes {
+            host.add_route_handler(path, *handler)?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers a route on the default host.
     pub fn add_route_handler(&mut self, path: &str, handler: RouteHandlerFn) -> Result<()> {
-        // Extract the method from the path
-        let method = path.split(' ').next().unwrap();
-        let method = HTTPMethod::parse_method(method).unwrap();
-
-        // Extract path, omitting the method
-        let path = path.split(' ').nth(1).unwrap();
-
-        // Extract the params from the path
-        let params = path
-            .split('/')
-            .filter_map(|part| {
-                if part.starts_with(':') {
-                    part.strip_prefix(':').map(|p| p.to_string())
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<String>>();
-
-        // Replace the params with regex patterns
-        let pattern = path
-            .split('/')
-            .collect::<Vec<&str>>()
-            .iter_mut()
-            .map(|part| {
-                if part.starts_with(':') {
-                    *part = "([^/]+)"
-                }
-                *part
-            })
-            .join("/");
-        // Add start and end anchors to the pattern to ensure it matches the entire path
-        let pattern = format!("^{}$", pattern.replace('/', "\\/"));
-        let pattern = Regex::new(&pattern)?;
-
-        println!(
-            "Method: {:?}, Path: {:?}, Pattern: {:?}, Params: {:?}",
-            method, path, pattern, params
-        );
+        self.default_host.add_route_handler(path, handler)
+    }
 
-        let route_handler = RouteHandler::new(handler, method, pattern, &params);
+    /// Registers routes on the default host.
+    pub fn route_handlers(&mut self, handlers: &[(&str, RouteHandlerFn)]) -> Result<()> {
+        self.default_host.add_route_handlers(handlers)
+    }
 
-        self.route_handlers.push(route_handler);
+    /// Adds a virtual host serving requests whose `Host` header matches one of `names`,
+    /// e.g. `example.com` or `*.example.com`, with routes of its own.
+    pub fn virtual_host(&mut self, names: &[&str], pub_dir: &str) -> Result<&mut VirtualHost> {
+        let mut host = VirtualHost::new(names, pub_dir)?;
+        Self::add_server_routes(&mut host, &self.server_routes)?;
 
-        Ok(())
+        self.hosts.push(host);
+
+        Ok(self.hosts.last_mut().unwrap())
     }
 
-    pub fn route_handlers(&mut self, handlers: &[(&str, RouteHandlerFn)]) -> Result<()> {
-        for (path, handler) in handlers {
-            self.add_route_handler(path, *handler)?;
-        }
+    /// The default host followed by the virtual hosts, to register routes served by all.
+    pub fn virtual_hosts_mut(&mut self) -> impl Iterator<Item = &mut VirtualHost> {
+        std::iter::once(&mut self.default_host).chain(self.hosts.iter_mut())
+    }
 
-        Ok(())
+    /// Registers a middleware that runs for every request, in registration order,
+    /// before the request is routed to its handler.
+    pub fn middleware(&mut self, middleware: impl Middleware + 'static) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+
+    /// Registers a check that must pass for `/readyz` to report the server as ready.
+    pub fn readiness_check<F, Fut>(&mut self, name: &str, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.info.health.add_check(name, check);
+    }
+
+    /// Returns a handle that stops the server gracefully, like SIGINT and SIGTERM do.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
     }
 
+    /// Serves connections until a shutdown is requested. In-flight requests are then given
+    /// up to the shutdown timeout to complete before `run` returns.
     pub async fn run(self) -> Result<()> {
-        println!(
-            "server listening on port {}",
-            self.listener.local_addr()?.port()
-        );
+        if let Some(tls) = self.tls {
+            tls.reload_on_sighup()?;
+        }
 
-        loop {
-            let (stream, _) = self.listener.accept().await?;
+        self.shutdown_handle
+            .shutdown_on_signals(self.pre_stop_delay)?;
+        reload::reload_on_sigusr2(
+            self.listeners
+                .iter()
+                .map(|listener| listener.socket.as_raw_fd())
+                .collect(),
+        )?;
+
+        let router = Router {
+            hosts: Arc::new(VirtualHosts::new(self.default_host, self.hosts)),
+            middlewares: Arc::new(self.middlewares),
+            info: self.info,
+            timeouts: self.timeouts,
+            request_limits: self.request_limits,
+            access_log: self.access_log,
+            tls: false,
+        };
 
-            let route_handler = self.route_handlers.clone();
-            let info = self.info.clone();
+        for proxy in router.hosts.proxies() {
+            tokio::spawn(proxy.clone().check_health(self.shutdown_handle.subscribe()));
+        }
 
-            tokio::spawn(async move {
-                let mut handler = Handler::new(stream, route_handler, info);
-                let _ = handler.handle().await;
-            });
+        // Receives `None` once every sender, one per connection and per accept loop, is dropped
+        let (drain, mut drained) = mpsc::channel::<()>(1);
+
+        let mut accept_loops = JoinSet::new();
+
+        for listener in self.listeners {
+            let tls = match listener.tls {
+                TlsMode::Off => "",
+                TlsMode::Required(_) => " (TLS)",
+                TlsMode::Detect(_) => " (TLS and plain)",
+            };
+            info!(
+                "Server listening on {}{}",
+                listener.socket.local_addr()?,
+                tls
+            );
+
+            accept_loops.spawn(listener.accept_loop(
+                router.clone(),
+                self.limiter.clone(),
+                self.shutdown_handle.subscribe(),
+                drain.clone(),
+            ));
+        }
+
+        drop(drain);
+
+        // The previous process stops once this one accepts connections
+        if let Some(parent) = listener::handed_over_by() {
+            // The previous process keeps serving instead
+            if let Err(e) = reload::notify_parent(parent) {
+                error!("Error taking over from the previous process: {:#}", e);
+                self.shutdown_handle.shutdown();
+            }
         }
+
+        // The accept loops return once the shutdown starts, or if accepting fails
+        while let Some(result) = accept_loops.join_next().await {
+            result??;
+        }
+
+        if timeout(self.shutdown_timeout, drained.recv())
+            .await
+            .is_err()
+        {
+            warn!("Shutdown timeout reached, closing remaining connections");
+        }
+
+        info!("Server stopped");
+
+        Ok(())
     }
 
     pub fn info(&self) -> &Info {
@@ -221,142 +780,605 @@ impl Server {
     }
 }
 
-pub struct Handler {
-    tcp_stream: TcpStream,
-    route_handlers: RouteHandlers,
+/// Builds an error response produced by the server itself rather than a handler.
+/// Its body carries the request ID, for clients to report alongside the error.
+pub fn error_response(code: u16, reason: &str, request_id: &str) -> ResponseBuilder {
+    ResponseBuilder::new()
+        .status(code, reason)
+        .header("Content-Type", "text/plain")
+        .header(request_id::HEADER, request_id)
+        .body(format!("{} {}\nRequest ID: {}\n", code, reason, request_id).as_bytes())
+}
+
+/// Routes parsed requests to their handlers, independently of the protocol they came in on.
+#[derive(Clone)]
+pub struct Router {
+    hosts: Arc<VirtualHosts>,
+    middlewares: Arc<Middlewares>,
     info: Info,
+    timeouts: Timeouts,
+    request_limits: RequestLimits,
+    access_log: Arc<AccessLog>,
+    /// Whether the connection the requests come from is encrypted.
+    tls: bool,
 }
 
-impl Handler {
-    pub fn new(stream: TcpStream, route_handlers: RouteHandlers, info: Info) -> Handler {
-        Handler {
-            tcp_stream: stream,
-            route_handlers,
-            info,
-        }
+impl Router {
+    /// The router for requests of a TLS connection.
+    fn with_tls(self) -> Router {
+        Router { tls: true, ..self }
     }
 
-    pub async fn handle(&mut self) -> Result<()> {
-        let request = self.read_request().await;
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
 
-        let response = match request {
-            Ok(mut request) => {
-                // Find the handler that matches the request's path
-                let handlers = self.find_matching_handlers(&mut request);
+    pub fn request_limits(&self) -> RequestLimits {
+        self.request_limits
+    }
 
-                // If no handlers match the request's path, return a 404 response
-                if handlers.is_empty() {
-                    Ok(ResponseBuilder::new().status(404, "Not Found"))
-                } else {
-                    // Find the handler that matches the request's method
-                    self.find_handler_for_method(&handlers, &request)
-                }
-            }
-            Err(e) => {
-                eprintln!("HTTP Error: {:?}", e);
+    /// Finds the client of a request, behind the trusted proxies it went through.
+    pub fn resolve_client(&self, request: &Request, peer_addr: SocketAddr) -> Client {
+        self.info
+            .trusted_proxies
+            .resolve(request, peer_addr, self.tls)
+    }
 
-                let (code, reason) = match e {
-                    HTTPError::IoError(_) => (500, "Internal Server Error"),
-                    HTTPError::IllegalMethod => (400, "Bad Request"),
-                    HTTPError::Other(_) => (400, "Bad Request"),
-                };
+    /// The peer as the client, for requests that could not be parsed.
+    pub fn peer_client(&self, peer_addr: SocketAddr) -> Client {
+        Client::peer(peer_addr, self.tls)
+    }
 
-                Ok(ResponseBuilder::new().status(code, reason))
-            }
-        };
+    /// Produces the response for a request, turning handler errors into a 500 response.
+    ///
+    /// Handlers are blocking functions, so they run on the blocking thread pool. One that
+    /// exceeds the handler timeout gets a 503 response closing the connection, but its thread
+    /// cannot be interrupted and keeps running until the handler returns.
+    ///
+    /// Everything logged while producing the response belongs to a `request` span.
+    ///
+    /// The request gets an ID if it has none yet, echoed in the response.
+    ///
+    /// `client` is that found by `resolve_client`. Also returns the path of the route that
+    /// handled the request, if one matched.
+    pub async fn respond(
+        &self,
+        mut request: Request,
+        peer_addr: SocketAddr,
+        client: Client,
+    ) -> (ResponseBuilder, Option<String>) {
+        request_id::assign(&mut request);
+        let request_id = request.request_id().unwrap_or_default().to_string();
+
+        // HTTP/1.1 clients must send the host, so that virtual hosts can tell requests apart
+        if !request.headers().contains_key("Host") && request.request_line().version() == "HTTP/1.1"
+        {
+            debug!(%peer_addr, "Request without Host header");
+
+            let response = error_response(400, "Bad Request", &request_id);
+
+            return (response.header("Connection", "close"), None);
+        }
+
+        // Repeated HTTP/2 fields are combined, and a single host cannot contain a comma
+        if request
+            .headers()
+            .get("Host")
+            .is_some_and(|host| host.contains(','))
+        {
+            debug!(%peer_addr, "Request with several hosts");
+
+            let response = error_response(400, "Bad Request", &request_id);
+
+            return (response.header("Connection", "close"), None);
+        }
 
-        let response = response.unwrap_or_else(|e| {
-            eprintln!("Error: {}", e);
-            ResponseBuilder::new().status(500, "Internal Server Error")
+        let host = self.hosts.select(client.host.as_deref()).clone();
+
+        let span = info_span!(
+            "request",
+            request_id,
+            method = ?request.method(),
+            path = request.request_line().path(),
+            %peer_addr,
+            client_ip = %client.ip,
+            route = field::Empty,
+        );
+
+        let started = Instant::now();
+        let router = self.clone();
+        let dispatch_span = span.clone();
+        let dispatch = task::spawn_blocking(move || {
+            dispatch_span.in_scope(|| {
+                let mut req_info =
+                    RequestInfo::new(request, peer_addr, router.info.clone(), host, client);
+                let response = router.dispatch(&mut req_info);
+
+                (response, req_info.route)
+            })
         });
 
-        let response = response.build().unwrap();
+        let result = timeout(self.timeouts.handler, dispatch)
+            .instrument(span.clone())
+            .await;
+
+        // Nothing is awaited past this point, so the span can be entered
+        let _entered = span.enter();
+
+        let (response, route) = match result {
+            Ok(Ok((Ok(response), route))) => (response, route),
+            Ok(Ok((Err(e), route))) => {
+                error!("Handler error: {:#}", e);
+                (
+                    error_response(500, "Internal Server Error", &request_id),
+                    route,
+                )
+            }
+            Ok(Err(e)) => {
+                error!("Handler panicked: {}", e);
+                (
+                    error_response(500, "Internal Server Error", &request_id),
+                    None,
+                )
+            }
+            Err(_) => {
+                warn!(timeout = ?self.timeouts.handler, "Handler timed out");
+                (
+                    error_response(503, "Service Unavailable", &request_id)
+                        .header("Connection", "close"),
+                    None,
+                )
+            }
+        };
 
-        self.tcp_stream.write_all(&response.as_bytes()).await?;
+        let response = response.header(request_id::HEADER, &request_id);
 
-        Ok(())
+        debug!(
+            status = response.get_status(),
+            elapsed = ?started.elapsed(),
+            "Request handled"
+        );
+
+        (response, route)
     }
-    fn find_matching_handlers(&self, request: &mut Request) -> Vec<&RouteHandler> {
-        self.route_handlers
-            .iter()
-            .filter(|handler| {
-                let path = request.request_line().path();
-
-                if handler.pattern().is_match(path) {
-                    // Parse params from the path
-                    let params = self.parse_params(handler, path);
-                    // Add the params to the request
-                    request.add_params(params);
-                    println!("{}", request);
-
-                    true
-                } else {
-                    false
-                }
-            })
-            .collect()
+
+    /// Records a request in the access log and the metrics, once its response was sent.
+    /// `bytes` is the size of the response body.
+    pub fn complete(&self, request_log: &RequestLog, status: u16, bytes: usize) {
+        self.access_log.log(request_log, status, bytes);
+        self.info.metrics.record(request_log, status, bytes);
     }
 
-    fn parse_params(&self, handler: &RouteHandler, path: &str) -> HashMap<String, String> {
-        let mut params = HashMap::new();
+    /// Runs the middlewares and the matching route handler for a parsed request.
+    fn dispatch(&self, req_info: &mut RequestInfo) -> Result<ResponseBuilder> {
+        // Find the handlers that match the request's path, for middlewares to know the route
+        let host = req_info.host.clone();
+        let handlers = self.find_matching_handlers(&host, req_info.request_mut());
+
+        // Requests with an unsupported method are still counted against the route
+        req_info.route = handlers.first().map(|handler| handler.path().to_string());
 
-        let captures = handler.pattern().captures(path).unwrap();
-        for (index, param_name) in handler.params.iter().enumerate() {
-            let param_value = captures.get(index + 1).unwrap().as_str();
-            let param_value = param_value.replace("%20", " ");
-            params.insert(param_name.to_string(), param_value.to_string());
+        let mut response = None;
+
+        // A middleware can answer the request itself, skipping the rest of the chain
+        for middleware in self.middlewares.iter() {
+            response = middleware.handle_request(req_info)?;
+
+            if response.is_some() {
+                break;
+            }
+        }
+
+        let mut response = match response {
+            Some(response) => response,
+            None => self.route(req_info, &handlers)?,
+        };
+
+        for middleware in self.middlewares.iter().rev() {
+            response = middleware.handle_response(req_info, response)?;
         }
 
-        params
+        Ok(response)
     }
 
-    fn find_handler_for_method(
+    fn route(
         &self,
+        req_info: &mut RequestInfo,
         handlers: &[&RouteHandler],
-        request: &Request,
     ) -> Result<ResponseBuilder> {
-        handlers
+        // If no handlers match the request's path, return a 404 response
+        if handlers.is_empty() {
+            Ok(ResponseBuilder::new().status(404, "Not Found"))
+        } else {
+            // Find the handler that matches the request's method
+            self.find_handler_for_method(handlers, req_info)
+        }
+    }
+
+    fn find_matching_handlers<'a>(
+        &self,
+        host: &'a VirtualHost,
+        request: &mut Request,
+    ) -> Vec<&'a RouteHandler> {
+        host.route_handlers()
             .iter()
-            .find_map(|handler| {
-                let cloned_request = request.clone();
-                if &handler.method == cloned_request.method() {
-                    let fn_params = RequestInfo::new(cloned_request, self.info.clone());
-                    Some((handler.handler_fn())(fn_params))
-                } else {
-                    None
+            .filter(|handler| {
+                match handler.match_path(request.request_line().path()) {
+                    Some(params) => {
+                        // Add the params to the request
+                        request.add_params(params);
+
+                        true
+                    }
+                    None => false,
                 }
             })
-            .unwrap_or(Ok(ResponseBuilder::new().status(405, "Method Not Allowed")))
+            .collect()
     }
 
-    async fn read_request(&mut self) -> Result<Request, HTTPError> {
-        let buffer = self.read_from_stream().await?;
+    fn find_handler_for_method(
+        &self,
+        handlers: &[&RouteHandler],
+        req_info: &mut RequestInfo,
+    ) -> Result<ResponseBuilder> {
+        let handler = handlers
+            .iter()
+            .find(|handler| &handler.method == req_info.request().method());
+
+        match handler {
+            Some(handler) => {
+                Span::current().record("route", handler.path());
+                req_info.route = Some(handler.path().to_string());
+
+                let fn_params = req_info.clone();
+                (handler.handler_fn())(fn_params)
+            }
+            None => Ok(ResponseBuilder::new().status(405, "Method Not Allowed")),
+        }
+    }
+}
 
-        let str_buffer = String::from_utf8(buffer.to_vec())?;
+pub struct Handler<S> {
+    stream: S,
+    /// Bytes read from the stream but not consumed by the current request yet.
+    buffer: Vec<u8>,
+    peer_addr: SocketAddr,
+    router: Router,
+    shutdown: Shutdown,
+}
 
-        Request::parse_request(&str_buffer)
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Handler<S> {
+    pub fn new(stream: S, peer_addr: SocketAddr, router: Router, shutdown: Shutdown) -> Handler<S> {
+        Handler {
+            stream,
+            buffer: Vec::new(),
+            peer_addr,
+            router,
+            shutdown,
+        }
     }
 
-    async fn read_from_stream(&mut self) -> Result<Vec<u8>, std::io::Error> {
-        let mut buffer = Vec::new();
+    /// Serves requests on the connection until the client closes it or asks to,
+    /// or the server shuts down.
+    pub async fn handle(mut self) -> Result<()> {
+        let mut shutdown = self.shutdown.clone();
+        let idle = self.router.timeouts().idle;
 
         loop {
-            let mut buf = [0; 1024];
-            let bytes_read = self.tcp_stream.read(&mut buf[..]).await?;
+            // Idle keep-alive connections are closed as soon as the shutdown starts,
+            // or after the idle timeout
+            if self.buffer.is_empty() {
+                let bytes_read = tokio::select! {
+                    bytes_read = self.fill_buffer() => bytes_read?,
+                    _ = shutdown.wait() => 0,
+                    _ = sleep(idle) => 0,
+                };
 
-            // If we read 0 bytes, we've reached the end of the stream
-            if bytes_read == 0 {
+                if bytes_read == 0 {
+                    return Ok(());
+                }
+            }
+
+            let request = match self.read_head().await {
+                // Clients with prior knowledge of HTTP/2 open the connection with its preface
+                Ok(head) if head == http2::PREFACE_HEAD => {
+                    let stream = Rewind::new([head, self.buffer].concat(), self.stream);
+                    return http2::serve(stream, self.peer_addr, self.router, self.shutdown).await;
+                }
+                Ok(head) => self.read_request(head).await,
+                Err(e) => Err(e),
+            };
+
+            let (response, keep_alive, request_log) = match request {
+                Ok(request) if http2::is_upgrade(&request) => {
+                    let stream = Rewind::new(self.buffer, self.stream);
+                    return http2::upgrade(
+                        stream,
+                        request,
+                        self.peer_addr,
+                        self.router,
+                        self.shutdown,
+                    )
+                    .await;
+                }
+                Ok(mut request) => {
+                    request_id::assign(&mut request);
+
+                    let keep_alive = Self::is_keep_alive(&request);
+                    let client = self.router.resolve_client(&request, self.peer_addr);
+                    let mut request_log = RequestLog::new(
+                        client.clone(),
+                        request.request_id().unwrap_or_default(),
+                        Some(&request),
+                    );
+
+                    let (response, route) =
+                        self.router.respond(request, self.peer_addr, client).await;
+                    request_log.set_route(route);
+
+                    (response, keep_alive, request_log)
+                }
+                Err(e) => {
+                    // Unparsed requests get an ID too, for clients to report the error
+                    let request_id = request_id::generate();
+
+                    debug!(
+                        peer_addr = %self.peer_addr,
+                        request_id,
+                        "Invalid request: {:?}", e
+                    );
+
+                    let (code, reason) = match e {
+                        HTTPError::IoError(_) => (500, "Internal Server Error"),
+                        HTTPError::IllegalMethod => (400, "Bad Request"),
+                        HTTPError::Timeout => (408, "Request Timeout"),
+                        HTTPError::HeadTooLarge => (431, "Request Header Fields Too Large"),
+                        HTTPError::BodyTooLarge => (413, "Payload Too Large"),
+                        HTTPError::Other(_) => (400, "Bad Request"),
+                    };
+
+                    // The rest of the stream cannot be trusted to start with a new request
+                    (
+                        error_response(code, reason, &request_id),
+                        false,
+                        RequestLog::new(self.router.peer_client(self.peer_addr), &request_id, None),
+                    )
+                }
+            };
+
+            let mut response = response.build().unwrap();
+
+            // Finish the current request, but do not wait for another one during shutdown.
+            // Responses can also ask for the connection to be closed.
+            let keep_alive = keep_alive
+                && !self.shutdown.is_shutting_down()
+                && !response
+                    .headers()
+                    .get("Connection")
+                    .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+
+            if response.status_code() != 101 {
+                response.set_header(
+                    "Connection",
+                    if keep_alive { "keep-alive" } else { "close" },
+                );
+            }
+
+            self.write(&response.as_bytes()).await?;
+
+            let chunked = response.is_chunked();
+
+            let bytes = match response.take_body_stream() {
+                Some(body_stream) if chunked => self.write_chunked_body(body_stream).await?,
+                Some(body_stream) => {
+                    let length = response
+                        .headers()
+                        .get("Content-Length")
+                        .and_then(|length| length.parse().ok())
+                        .unwrap_or_default();
+
+                    self.write_body_stream(body_stream, length).await?
+                }
+                None => response.body().len(),
+            };
+
+            self.router
+                .complete(&request_log, response.status_code(), bytes);
+
+            // After switching protocols, the connection belongs to the upgrade callback
+            if let Some(upgrade) = response.take_upgrade() {
+                let stream = Rewind::new(self.buffer, self.stream);
+                return upgrade(Box::new(stream)).await;
+            }
+
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    /// HTTP/1.1 connections are persistent unless the client asks otherwise,
+    /// while HTTP/1.0 ones are only kept alive on request.
+    fn is_keep_alive(request: &Request) -> bool {
+        match request.request_line().version() {
+            "HTTP/1.1" => !request.has_header_token("Connection", "close"),
+            _ => request.has_header_token("Connection", "keep-alive"),
+        }
+    }
+
+    /// Writes chunks as they are produced, until the body stream ends.
+    /// A write error means the client went away, which drops the stream and lets its
+    /// producer notice.
+    ///
+    /// Streams are ended early when the server shuts down, as they may never end otherwise.
+    ///
+    /// Returns the size of the body, without the chunk framing.
+    async fn write_chunked_body(&mut self, mut body_stream: BodyStream) -> Result<usize> {
+        let mut shutdown = self.shutdown.clone();
+        let mut bytes = 0;
+
+        loop {
+            let chunk = tokio::select! {
+                chunk = body_stream.recv() => chunk,
+                _ = shutdown.wait() => None,
+            };
+
+            let Some(chunk) = chunk else {
                 break;
+            };
+
+            // An empty chunk would mark the end of the body
+            if chunk.is_empty() {
+                continue;
             }
 
-            buffer.extend_from_slice(&buf[..bytes_read]);
+            let mut frame = format!("{:x}\r\n", chunk.len()).into_bytes();
+            frame.extend_from_slice(&chunk);
+            frame.extend_from_slice(b"\r\n");
+
+            self.write(&frame).await?;
+            bytes += chunk.len();
+        }
+
+        self.write(b"0\r\n\r\n").await?;
+
+        Ok(bytes)
+    }
+
+    /// Writes chunks as they are produced, as is, for bodies whose length was announced by
+    /// `Content-Length`. A body ending short of that length, e.g. during a shutdown, fails the
+    /// connection, as the client would otherwise wait for the rest.
+    ///
+    /// Returns the size of the body.
+    async fn write_body_stream(
+        &mut self,
+        mut body_stream: BodyStream,
+        length: usize,
+    ) -> Result<usize> {
+        let mut shutdown = self.shutdown.clone();
+        let mut bytes = 0;
 
-            // If we read less than the buffer size, we've read the entire request
-            if bytes_read < buf.len() {
+        loop {
+            let chunk = tokio::select! {
+                chunk = body_stream.recv() => chunk,
+                _ = shutdown.wait() => None,
+            };
+
+            let Some(chunk) = chunk else {
                 break;
+            };
+
+            self.write(&chunk).await?;
+            bytes += chunk.len();
+        }
+
+        if bytes != length {
+            return Err(anyhow!(
+                "Body of {} bytes sent for a Content-Length of {}",
+                bytes,
+                length
+            ));
+        }
+
+        Ok(bytes)
+    }
+
+    /// Writes and flushes bytes, giving up if the client does not accept them
+    /// within the write timeout.
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let write_timeout = self.router.timeouts().write;
+
+        let write = async {
+            self.stream.write_all(bytes).await?;
+            self.stream.flush().await
+        };
+
+        timeout(write_timeout, write)
+            .await
+            .map_err(|_| anyhow!("Write timed out"))??;
+
+        Ok(())
+    }
+
+    async fn read_request(&mut self, head: Vec<u8>) -> Result<Request, HTTPError> {
+        if Request::find_head_end(&head).is_none() {
+            return Err(HTTPError::Other(
+                "Invalid request: malformed HTTP".to_string(),
+            ));
+        }
+        let head = String::from_utf8(head)?;
+
+        let mut request = Request::parse_head(&head)?;
+
+        // Chunked request bodies are not decoded, and reading them by Content-Length instead
+        // would take their chunks for the next request
+        if request.headers().contains_key("Transfer-Encoding") {
+            return Err(HTTPError::Other(
+                "Invalid request: Transfer-Encoding is not supported".to_string(),
+            ));
+        }
+
+        // Read the body announced by Content-Length as raw bytes
+        let content_length = request.content_length()?;
+        if content_length > self.router.request_limits().max_body_size {
+            return Err(HTTPError::BodyTooLarge);
+        }
+        let body = self.read_body(content_length).await?;
+        request.set_body(body);
+
+        Ok(request)
+    }
+
+    /// Reads from the stream until the blank line terminating the request head, and returns
+    /// the head. If the stream ends first, whatever was read is returned.
+    async fn read_head(&mut self) -> Result<Vec<u8>, HTTPError> {
+        let deadline = Instant::now() + self.router.timeouts().header_read;
+        let max_head_size = self.router.request_limits().max_head_size;
+
+        loop {
+            if let Some(head_end) = Request::find_head_end(&self.buffer) {
+                if head_end > max_head_size {
+                    return Err(HTTPError::HeadTooLarge);
+                }
+
+                let rest = self.buffer.split_off(head_end);
+                return Ok(std::mem::replace(&mut self.buffer, rest));
+            }
+
+            // The buffer only holds the start of the head, which is already too large
+            if self.buffer.len() >= max_head_size {
+                return Err(HTTPError::HeadTooLarge);
+            }
+
+            // If we read 0 bytes, we've reached the end of the stream
+            if timeout_at(deadline, self.fill_buffer()).await?? == 0 {
+                return Ok(std::mem::take(&mut self.buffer));
             }
         }
+    }
+
+    async fn read_body(&mut self, content_length: usize) -> Result<Vec<u8>, HTTPError> {
+        let deadline = Instant::now() + self.router.timeouts().body_read;
+
+        while self.buffer.len() < content_length {
+            if timeout_at(deadline, self.fill_buffer()).await?? == 0 {
+                return Err(HTTPError::Other(
+                    "Invalid request: body shorter than Content-Length".to_string(),
+                ));
+            }
+        }
+
+        let rest = self.buffer.split_off(content_length);
+
+        Ok(std::mem::replace(&mut self.buffer, rest))
+    }
+
+    async fn fill_buffer(&mut self) -> Result<usize, std::io::Error> {
+        let mut buf = [0; 1024];
+        let bytes_read = self.stream.read(&mut buf[..]).await?;
+
+        self.buffer.extend_from_slice(&buf[..bytes_read]);
 
-        Ok(buffer)
+        Ok(bytes_read)
     }
 }
@@ -0,0 +1,132 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::sleep,
+};
+use tracing::{info, warn};
+
+/// Starts a graceful shutdown of the server it was obtained from.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+    /// Set once the server was asked to stop, possibly before the shutdown starts.
+    stopping: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        let (sender, _) = watch::channel(false);
+
+        ShutdownHandle {
+            sender: Arc::new(sender),
+            stopping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.sender.send_replace(true);
+    }
+
+    /// Marks the server as stopping right away, but only starts the shutdown after `delay`.
+    /// Connections are still accepted meanwhile, giving load balancers polling `/readyz`
+    /// the time to take the server out of rotation.
+    pub fn shutdown_after(&self, delay: Duration) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        if delay.is_zero() {
+            self.shutdown();
+            return;
+        }
+
+        let handle = self.clone();
+
+        tokio::spawn(async move {
+            sleep(delay).await;
+            handle.shutdown();
+        });
+    }
+
+    pub fn subscribe(&self) -> Shutdown {
+        Shutdown {
+            receiver: self.sender.subscribe(),
+            stopping: self.stopping.clone(),
+        }
+    }
+
+    /// Starts the shutdown on SIGINT or SIGTERM, once `pre_stop_delay` has passed.
+    /// A second signal exits immediately, without waiting for connections to drain.
+    pub fn shutdown_on_signals(&self, pre_stop_delay: Duration) -> Result<()> {
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let handle = self.clone();
+
+        tokio::spawn(async move {
+            for count in 0.. {
+                tokio::select! {
+                    _ = interrupt.recv() => {},
+                    _ = terminate.recv() => {},
+                }
+
+                if count > 0 {
+                    warn!("Forced shutdown");
+                    std::process::exit(1);
+                }
+
+                info!(
+                    ?pre_stop_delay,
+                    "Shutting down gracefully, send the signal again to force exit"
+                );
+                handle.shutdown_after(pre_stop_delay);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lets connection tasks find out that the server is shutting down.
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Whether the server was asked to stop, including during the delay before the shutdown.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the shutdown has started.
+    pub async fn wait(&mut self) {
+        if self
+            .receiver
+            .wait_for(|shutting_down| *shutting_down)
+            .await
+            .is_err()
+        {
+            // Every handle is gone, so the shutdown can never start
+            std::future::pending::<()>().await;
+        }
+    }
+}
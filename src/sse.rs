@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use tokio::{
+    sync::mpsc,
+    time::{interval_at, Instant},
+};
+
+use crate::{request::Request, response::ResponseBuilder};
+
+/// Default interval between keep-alive comments, short enough for most proxies
+/// not to consider the connection idle.
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Sends events to a client. Sending fails once the client has disconnected,
+/// and [`mpsc::Sender::closed`] resolves at that point.
+pub type EventSender = mpsc::Sender<Event>;
+
+/// A single Server-Sent Event.
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        Event {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the event ID, sent back by the browser as `Last-Event-ID` when it reconnects.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// Sets the event type, dispatched to the matching `addEventListener` on the client.
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// Sets how long the client waits before reconnecting after losing the connection.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut event = String::new();
+
+        if let Some(id) = &self.id {
+            event.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(name) = &self.event {
+            event.push_str(&format!("event: {}\n", name));
+        }
+        if let Some(retry) = self.retry {
+            event.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+
+        // Multi-line data is sent as one data field per line
+        for line in self.data.replace("\r\n", "\n").split('\n') {
+            event.push_str(&format!("data: {}\n", line));
+        }
+
+        event.push('\n');
+
+        event.into_bytes()
+    }
+}
+
+/// Field values other than data cannot span several lines.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// The ID of the last event a reconnecting client received, if any.
+pub fn last_event_id(request: &Request) -> Option<&str> {
+    request.headers().get("Last-Event-Id").map(|id| id.as_str())
+}
+
+/// Creates a channel for pushing events to a client. The handler returns the
+/// `EventStream` as its response and passes the sender to a task producing events.
+pub fn channel(buffer: usize) -> (EventSender, EventStream) {
+    let (sender, events) = mpsc::channel(buffer);
+
+    let stream = EventStream {
+        events,
+        keep_alive: DEFAULT_KEEP_ALIVE,
+    };
+
+    (sender, stream)
+}
+
+pub struct EventStream {
+    events: mpsc::Receiver<Event>,
+    keep_alive: Duration,
+}
+
+impl EventStream {
+    /// Sets the interval between keep-alive comments sent while no event is produced.
+    /// They also let the server notice clients that disconnected. Zero disables them.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn into_response(self) -> ResponseBuilder {
+        let EventStream {
+            mut events,
+            keep_alive,
+        } = self;
+
+        let (body_sender, body_stream) = mpsc::channel(16);
+
+        // The stream ends when the producer drops its sender or the client disconnects,
+        // whichever comes first. Dropping `events` then lets the producer know.
+        tokio::spawn(async move {
+            let mut keep_alive = (!keep_alive.is_zero())
+                .then(|| interval_at(Instant::now() + keep_alive, keep_alive));
+
+            loop {
+                let chunk = tokio::select! {
+                    event = events.recv() => match event {
+                        Some(event) => event.as_bytes(),
+                        None => break,
+                    },
+                    _ = async { keep_alive.as_mut().unwrap().tick().await },
+                        if keep_alive.is_some() => b": keep-alive\n\n".to_vec(),
+                    _ = body_sender.closed() => break,
+                };
+
+                if body_sender.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        ResponseBuilder::new()
+            .status(200, "OK")
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .body_stream(body_stream)
+    }
+}
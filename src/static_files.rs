@@ -0,0 +1,121 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+use crate::{
+    request::Request, response::ResponseBuilder, server::RequestInfo, utils::accepts_encoding,
+};
+
+/// Precompressed sibling variants, in order of preference, as `(Content-Encoding, file extension)`.
+const PRECOMPRESSED_VARIANTS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Serves a file from disk, preferring a precompressed sibling (e.g. `app.js.br` or `app.js.gz`)
+/// when the client accepts its encoding.
+pub fn serve_file(request: &Request, path: &Path, content_type: &str) -> ResponseBuilder {
+    let accept_encoding = request
+        .headers()
+        .get("Accept-Encoding")
+        .map(|x| x.as_str())
+        .unwrap_or_default();
+
+    let mut has_variants = false;
+
+    for (encoding, extension) in PRECOMPRESSED_VARIANTS {
+        let variant_path = sibling_path(path, extension);
+
+        if !variant_path.is_file() {
+            continue;
+        }
+        has_variants = true;
+
+        if !accepts_encoding(accept_encoding, encoding) {
+            continue;
+        }
+
+        if let Ok(file) = fs::read(&variant_path) {
+            return ResponseBuilder::new()
+                .status(200, "OK")
+                .header("Content-Type", content_type)
+                .header("Content-Encoding", encoding)
+                .header("Vary", "Accept-Encoding")
+                .body(&file);
+        }
+    }
+
+    let response = match fs::read(path) {
+        Ok(file) => ResponseBuilder::new()
+            .status(200, "OK")
+            .header("Content-Type", content_type)
+            .body(&file),
+        Err(_) => ResponseBuilder::new()
+            .status(404, "Not Found")
+            .header("Content-Type", "text/plain"),
+    };
+
+    // Caches must not reuse the identity response for clients that accept a compressed variant
+    if has_variants {
+        response.header("Vary", "Accept-Encoding")
+    } else {
+        response
+    }
+}
+
+fn sibling_path(path: &Path, extension: &str) -> std::path::PathBuf {
+    let mut variant = path.as_os_str().to_owned();
+    variant.push(".");
+    variant.push(extension);
+    variant.into()
+}
+
+/// Route handler serving the files of a directory route from the configuration,
+/// the file path being the rest of the request path.
+pub fn handle_directory(req_info: RequestInfo) -> Result<ResponseBuilder> {
+    let not_found = ResponseBuilder::new()
+        .status(404, "Not Found")
+        .header("Content-Type", "text/plain");
+
+    let Some(dir) = req_info
+        .route()
+        .and_then(|route| req_info.host().directory(route))
+    else {
+        return Ok(not_found);
+    };
+
+    let request = req_info.request();
+    let file = request.params().get("file").map_or("", String::as_str);
+
+    // Never leave the directory
+    if file.split('/').any(|segment| {
+        segment.is_empty() || segment == "." || segment == ".." || segment.contains('\\')
+    }) {
+        return Ok(not_found);
+    }
+
+    let path = Path::new(dir).join(file);
+
+    Ok(serve_file(request, &path, content_type(&path)))
+}
+
+/// Guesses the media type of a file from its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
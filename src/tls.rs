@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::{default_provider, sign::any_supported_type},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{error, info};
+
+use crate::{config::TlsConfig, http2};
+
+/// Certificates currently served, swapped as a whole on reload.
+#[derive(Debug, Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Picks the certificate matching the SNI server name of the client,
+/// falling back to the default certificate.
+#[derive(Debug, Default)]
+struct CertResolver {
+    certificates: RwLock<Certificates>,
+}
+
+impl CertResolver {
+    fn find(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap();
+
+        let by_name = server_name.and_then(|name| {
+            let name = name.to_ascii_lowercase();
+
+            // An exact match wins over a wildcard certificate for the parent domain
+            certificates.by_name.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                certificates.by_name.get(&format!("*.{}", parent))
+            })
+        });
+
+        by_name.or(certificates.default.as_ref()).cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+/// HTTPS termination state shared by every TLS connection.
+pub struct Tls {
+    config: TlsConfig,
+    resolver: Arc<CertResolver>,
+    acceptor: TlsAcceptor,
+}
+
+impl Tls {
+    pub fn new(config: TlsConfig) -> Result<Tls> {
+        let resolver = Arc::new(CertResolver::default());
+
+        let mut server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+
+        // Offer HTTP/2, falling back to HTTP/1.1 for clients that do not support it
+        server_config.alpn_protocols = vec![http2::ALPN_PROTOCOL.to_vec(), b"http/1.1".to_vec()];
+
+        let tls = Tls {
+            config,
+            resolver,
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        };
+        tls.reload()?;
+
+        Ok(tls)
+    }
+
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.config.port
+    }
+
+    /// Reads every certificate and key from disk again.
+    /// If any of them fails to load, the certificates in use are kept.
+    pub fn reload(&self) -> Result<()> {
+        let default = load_certified_key(&self.config.cert_path, &self.config.key_path)?;
+
+        let mut by_name = HashMap::new();
+        for (name, cert_path, key_path) in &self.config.sni {
+            by_name.insert(name.clone(), load_certified_key(cert_path, key_path)?);
+        }
+
+        *self.resolver.certificates.write().unwrap() = Certificates {
+            default: Some(default),
+            by_name,
+        };
+
+        Ok(())
+    }
+
+    /// Reloads the certificates every time the process receives SIGHUP.
+    pub fn reload_on_sighup(self: Arc<Self>) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match self.reload() {
+                    Ok(_) => info!("TLS certificates reloaded"),
+                    Err(e) => error!("Error reloading TLS certificates: {:#}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<Arc<CertifiedKey>> {
+    let mut cert_reader = BufReader::new(
+        File::open(cert_path).with_context(|| format!("Cannot open certificate {}", cert_path))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate {}", cert_path))?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", cert_path));
+    }
+
+    let mut key_reader = BufReader::new(
+        File::open(key_path).with_context(|| format!("Cannot open private key {}", key_path))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .with_context(|| format!("Invalid private key {}", key_path))?
+        .ok_or(anyhow!("No private key found in {}", key_path))?;
+
+    let signing_key = any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported private key {}: {}", key_path, e))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
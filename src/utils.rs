@@ -1,9 +1,96 @@
 use anyhow::Result;
 use flate2::{write::GzEncoder, Compression};
-use std::io::Write;
+use std::{
+    io::Write,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 pub fn gzip_str(string: &str) -> Result<Vec<u8>> {
     let mut e = GzEncoder::new(Vec::new(), Compression::default());
     e.write_all(string.as_bytes())?;
     e.finish().map_err(|e| e.into())
 }
+
+/// Checks whether an `Accept-Encoding` header value accepts the given content coding.
+/// A coding listed with `q=0` is treated as explicitly refused, and `*` covers unlisted codings.
+pub fn accepts_encoding(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';').map(|x| x.trim());
+        let name = parts.next().unwrap_or_default();
+
+        // Only a non-zero quality value accepts the coding
+        let accepted = parts
+            .filter_map(|param| param.strip_prefix("q="))
+            .all(|q| q.parse::<f32>().map(|q| q > 0.0).unwrap_or(false));
+
+        if name.eq_ignore_ascii_case(coding) {
+            return accepted;
+        }
+
+        if name == "*" {
+            wildcard = Some(accepted);
+        }
+    }
+
+    wildcard.unwrap_or(false)
+}
+
+/// A stream that replays already-read bytes before reading from the inner stream again.
+/// Used when a connection is handed over to another protocol after part of it was consumed.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Rewind {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.position < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.position);
+            buf.put_slice(&this.prefix[this.position..this.position + len]);
+            this.position += len;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
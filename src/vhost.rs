@@ -0,0 +1,386 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use regex::Regex;
+use tracing::debug;
+
+use crate::{
+    config::{ProxyConfig, RouteConfig, RouteTarget},
+    proxy::{self, Proxy},
+    request::HTTPMethod,
+    server::{RouteHandler, RouteHandlerFn},
+    static_files,
+};
+
+/// Routes and a public directory served for a set of `Host` names.
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    /// Names like `example.com`, or `*.example.com` for any subdomain of `example.com`.
+    names: Vec<String>,
+    pub_dir: String,
+    route_handlers: Vec<RouteHandler>,
+    /// Directories served by the routes of the configuration, by route path.
+    directories: HashMap<String, String>,
+    /// Proxies forwarding the requests of proxy routes, by route path.
+    proxies: HashMap<String, Arc<Proxy>>,
+}
+
+impl VirtualHost {
+    pub fn new(names: &[&str], pub_dir: &str) -> Result<VirtualHost> {
+        let names = names
+            .iter()
+            .map(|name| parse_name(name))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(VirtualHost {
+            names,
+            pub_dir: pub_dir.to_string(),
+            route_handlers: Vec::new(),
+            directories: HashMap::new(),
+            proxies: HashMap::new(),
+        })
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn pub_dir(&self) -> &str {
+        &self.pub_dir
+    }
+
+    pub fn route_handlers(&self) -> &[RouteHandler] {
+        &self.route_handlers
+    }
+
+    /// The directory served by a route registered from the configuration.
+    pub fn directory(&self, route: &str) -> Option<&str> {
+        self.directories.get(route).map(String::as_str)
+    }
+
+    /// The proxy forwarding the requests of a proxy route.
+    pub fn proxy(&self, route: &str) -> Option<&Proxy> {
+        self.proxies.get(route).map(Arc::as_ref)
+    }
+
+    pub fn proxies(&self) -> impl Iterator<Item = &Arc<Proxy>> {
+        self.proxies.values()
+    }
+
+    pub fn add_route_handler(&mut self, path: &str, handler: RouteHandlerFn) -> Result<()> {
+        // Extract the method from the path
+        let method = path.split(' ').next().unwrap();
+        let method = HTTPMethod::parse_method(method).unwrap();
+
+        // Extract path, omitting the method
+        let path = path.split(' ').nth(1).unwrap();
+
+        // Extract the params from the path
+        let params = path
+            .split('/')
+            .filter_map(|part| {
+                part.strip_prefix(':')
+                    .or_else(|| part.strip_prefix('*'))
+                    .map(|p| p.to_string())
+            })
+            .collect::<Vec<String>>();
+
+        // Replace the params with regex patterns, matching the rest of the path literally,
+        // so that `/v1.0` does not match `/v1x0`
+        let pattern = path
+            .split('/')
+            .map(|part| {
+                if part.starts_with(':') {
+                    "([^/]+)".to_string()
+                } else if part.starts_with('*') {
+                    // A wildcard matches the rest of the path, slashes included
+                    "(.+)".to_string()
+                } else {
+                    regex::escape(part)
+                }
+            })
+            .join("/");
+        // Add start and end anchors to the pattern to ensure it matches the entire path
+        let pattern = format!("^{}$", pattern.replace('/', "\\/"));
+        let pattern = Regex::new(&pattern)?;
+
+        debug!(?method, path, %pattern, ?params, hosts = ?self.names, "Route registered");
+
+        let route_handler = RouteHandler::new(handler, method, path, pattern, &params);
+
+        self.route_handlers.push(route_handler);
+
+        Ok(())
+    }
+
+    pub fn add_route_handlers(&mut self, handlers: &[(&str, RouteHandlerFn)]) -> Result<()> {
+        for (path, handler) in handlers {
+            self.add_route_handler(path, *handler)?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers the routes of the configuration.
+    pub fn add_routes(&mut self, routes: &[RouteConfig]) -> Result<()> {
+        for route in routes {
+            match &route.target {
+                RouteTarget::Directory(dir) => {
+                    let path = format!("{}/*file", route.path);
+
+                    self.add_route_handler(
+                        &format!("GET {}", path),
+                        static_files::handle_directory,
+                    )?;
+                    self.directories.insert(path, dir.clone());
+                }
+                RouteTarget::Proxy(config) => self.add_proxy(&route.path, config)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forwards every request under a path prefix, e.g. `/api`, to upstream servers.
+    /// The path is forwarded unchanged, prefix included.
+    pub fn add_proxy(&mut self, path: &str, config: &ProxyConfig) -> Result<()> {
+        let path = format!("{}/*path", path.trim_end_matches('/'));
+
+        for method in proxy::METHODS {
+            self.add_route_handler(&format!("{} {}", method, path), proxy::handle_proxy)?;
+        }
+
+        self.proxies.insert(path, Arc::new(Proxy::new(config)));
+
+        Ok(())
+    }
+}
+
+/// Checks a host name, lowercasing it.
+pub fn parse_name(name: &str) -> Result<String> {
+    let name = name.to_ascii_lowercase();
+    let domain = name.strip_prefix("*.").unwrap_or(&name);
+
+    if domain.is_empty() || domain.contains(['*', ' ', '/', ':']) {
+        return Err(anyhow!(
+            "Invalid host name {}, expected a name like example.com or *.example.com",
+            name
+        ));
+    }
+
+    Ok(name)
+}
+
+/// Virtual hosts, selected by the `Host` header of requests.
+#[derive(Debug)]
+pub struct VirtualHosts {
+    /// Serves requests matching no other host, or without a `Host` header.
+    default: Arc<VirtualHost>,
+    hosts: Vec<Arc<VirtualHost>>,
+}
+
+impl VirtualHosts {
+    pub fn new(default: VirtualHost, hosts: Vec<VirtualHost>) -> VirtualHosts {
+        VirtualHosts {
+            default: Arc::new(default),
+            hosts: hosts.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// The proxies of every host, whose upstreams are checked in the background.
+    pub fn proxies(&self) -> impl Iterator<Item = &Arc<Proxy>> {
+        std::iter::once(&self.default)
+            .chain(&self.hosts)
+            .flat_map(|host| host.proxies())
+    }
+
+    /// Finds the host serving a `Host` header value. Exact names win over wildcards,
+    /// and longer wildcards over shorter ones.
+    pub fn select(&self, host: Option<&str>) -> &Arc<VirtualHost> {
+        let Some(host) = host.map(normalize) else {
+            return &self.default;
+        };
+
+        let exact = self
+            .hosts
+            .iter()
+            .find(|virtual_host| virtual_host.names.contains(&host));
+
+        if let Some(virtual_host) = exact {
+            return virtual_host;
+        }
+
+        self.hosts
+            .iter()
+            .flat_map(|virtual_host| {
+                virtual_host
+                    .names
+                    .iter()
+                    .filter_map(|name| name.strip_prefix('*'))
+                    .filter(|suffix| host.ends_with(suffix))
+                    .map(move |suffix| (suffix.len(), virtual_host))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map_or(&self.default, |(_, virtual_host)| virtual_host)
+    }
+}
+
+/// Lowercases a `Host` header value and strips its port and trailing dot,
+/// e.g. `Example.com.:8080` becomes `example.com`.
+fn normalize(host: &str) -> String {
+    let host = host.trim().to_ascii_lowercase();
+
+    // IPv6 addresses are bracketed, e.g. [::1]:8080
+    let host = match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => &host,
+    };
+
+    host.trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::response::ResponseBuilder;
+
+    fn host_with_routes(paths: &[&str]) -> VirtualHost {
+        let mut host = VirtualHost::new(&[], "public").unwrap();
+
+        for path in paths {
+            host.add_route_handler(path, |_| Ok(ResponseBuilder::new()))
+                .unwrap();
+        }
+
+        host
+    }
+
+    /// The params of the first route matching `path`, if any.
+    fn match_path(host: &VirtualHost, path: &str) -> Option<Vec<(String, String)>> {
+        host.route_handlers()
+            .iter()
+            .find_map(|handler| handler.match_path(path))
+            .map(|params| params.into_iter().sorted().collect())
+    }
+
+    fn params(params: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(
+            params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn captures_params_and_wildcards() {
+        let host = host_with_routes(&["GET /files/:filename", "GET /assets/*file"]);
+
+        assert_eq!(
+            match_path(&host, "/files/a%20b.txt"),
+            params(&[("filename", "a b.txt")])
+        );
+        assert_eq!(
+            match_path(&host, "/assets/js/app.js"),
+            params(&[("file", "js/app.js")])
+        );
+        assert_eq!(match_path(&host, "/files/a/b"), None);
+        assert_eq!(match_path(&host, "/assets/"), None);
+    }
+
+    #[test]
+    fn leaves_the_query_string_out() {
+        let host = host_with_routes(&["GET /", "GET /assets/*file", "GET /echo/:text"]);
+
+        assert_eq!(
+            match_path(&host, "/assets/app.js?v=3"),
+            params(&[("file", "app.js")])
+        );
+        assert_eq!(
+            match_path(&host, "/echo/abc?lang=en"),
+            params(&[("text", "abc")])
+        );
+        assert_eq!(match_path(&host, "/?utm_source=feed"), params(&[]));
+    }
+
+    #[test]
+    fn matches_literal_segments_literally() {
+        let host = host_with_routes(&["GET /v1.0/items", "GET /a+b/(c)"]);
+
+        assert_eq!(match_path(&host, "/v1.0/items"), params(&[]));
+        assert_eq!(match_path(&host, "/v1x0/items"), None);
+        assert_eq!(match_path(&host, "/a+b/(c)"), params(&[]));
+        assert_eq!(match_path(&host, "/aab/c"), None);
+    }
+
+    fn hosts() -> VirtualHosts {
+        VirtualHosts::new(
+            VirtualHost::new(&[], "default").unwrap(),
+            vec![
+                VirtualHost::new(&["example.com", "www.example.com"], "example").unwrap(),
+                VirtualHost::new(&["*.example.com"], "subdomains").unwrap(),
+                VirtualHost::new(&["*.api.example.com"], "api").unwrap(),
+            ],
+        )
+    }
+
+    fn select<'a>(hosts: &'a VirtualHosts, host: Option<&str>) -> &'a str {
+        hosts.select(host).pub_dir()
+    }
+
+    #[test]
+    fn selects_hosts_by_exact_name() {
+        let hosts = hosts();
+
+        assert_eq!(select(&hosts, Some("example.com")), "example");
+        assert_eq!(select(&hosts, Some("www.example.com")), "example");
+    }
+
+    #[test]
+    fn ignores_the_case_port_and_trailing_dot_of_names() {
+        let hosts = hosts();
+
+        assert_eq!(select(&hosts, Some("Example.COM")), "example");
+        assert_eq!(select(&hosts, Some("example.com:8080")), "example");
+        assert_eq!(select(&hosts, Some("example.com.")), "example");
+        assert_eq!(select(&hosts, Some(" example.com.:443 ")), "example");
+    }
+
+    #[test]
+    fn prefers_longer_wildcards() {
+        let hosts = hosts();
+
+        assert_eq!(select(&hosts, Some("blog.example.com")), "subdomains");
+        assert_eq!(select(&hosts, Some("v1.api.example.com")), "api");
+        assert_eq!(select(&hosts, Some("api.example.com")), "subdomains");
+    }
+
+    #[test]
+    fn falls_back_to_the_default_host() {
+        let hosts = hosts();
+
+        assert_eq!(select(&hosts, None), "default");
+        assert_eq!(select(&hosts, Some("example.org")), "default");
+        assert_eq!(select(&hosts, Some("notexample.com")), "default");
+        assert_eq!(select(&hosts, Some("[::1]:8080")), "default");
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        for name in [
+            "",
+            "*.",
+            "*.*.example.com",
+            "example.com:80",
+            "exa mple.com",
+            "a/b",
+        ] {
+            assert!(parse_name(name).is_err(), "{:?}", name);
+        }
+
+        assert_eq!(parse_name("*.Example.com").unwrap(), "*.example.com");
+    }
+}
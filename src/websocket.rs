@@ -0,0 +1,546 @@
+use std::{future::Future, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    sync::Mutex,
+};
+use tracing::error;
+
+use crate::{
+    request::{HTTPMethod, Request},
+    response::{ResponseBuilder, Upgraded},
+};
+
+/// Appended to the client key before hashing, as defined by RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message, after reassembly and decompression, accepted from a client.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Trailer removed from each compressed message by permessage-deflate (RFC 7692).
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Answers a WebSocket opening handshake. Once the `101 Switching Protocols` response
+/// is written, `handler` is called with the established connection.
+///
+/// Requests that are not valid WebSocket handshakes get a 400 or 426 response instead.
+pub fn upgrade<F, Fut>(request: &Request, handler: F) -> Result<ResponseBuilder>
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let headers = request.headers();
+
+    if request.method() != &HTTPMethod::GET
+        || request.request_line().version() != "HTTP/1.1"
+        || !request.has_header_token("Upgrade", "websocket")
+        || !request.has_header_token("Connection", "Upgrade")
+    {
+        return Ok(ResponseBuilder::new()
+            .status(426, "Upgrade Required")
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Content-Type", "text/plain")
+            .body("Expected a WebSocket upgrade request".as_bytes()));
+    }
+
+    if headers.get("Sec-Websocket-Version").map(|x| x.as_str()) != Some("13") {
+        return Ok(ResponseBuilder::new()
+            .status(426, "Upgrade Required")
+            .header("Sec-WebSocket-Version", "13"));
+    }
+
+    // The key must be a base64-encoded 16-byte nonce
+    let key = match headers.get("Sec-Websocket-Key") {
+        Some(key) if STANDARD.decode(key).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => {
+            return Ok(ResponseBuilder::new()
+                .status(400, "Bad Request")
+                .header("Content-Type", "text/plain")
+                .body("Invalid Sec-WebSocket-Key".as_bytes()))
+        }
+    };
+
+    let mut response = ResponseBuilder::new()
+        .status(101, "Switching Protocols")
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &accept_key(key));
+
+    let deflate = headers
+        .get("Sec-Websocket-Extensions")
+        .and_then(|offers| DeflateConfig::negotiate(offers));
+
+    if let Some(deflate) = &deflate {
+        response = response.header("Sec-WebSocket-Extensions", &deflate.response_header());
+    }
+
+    Ok(response.on_upgrade(move |upgraded| async move {
+        let websocket = WebSocket::new(upgraded, deflate);
+        let writer = websocket.writer();
+
+        // Close the connection on behalf of handlers that return without closing it
+        match handler(websocket).await {
+            Ok(_) => writer.close(CLOSE_NORMAL, "").await,
+            Err(e) => {
+                error!("WebSocket handler error: {:#}", e);
+                writer.close(CLOSE_INTERNAL_ERROR, "").await
+            }
+        }
+    }))
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+
+    STANDARD.encode(sha1.finalize())
+}
+
+/// Parameters of the permessage-deflate extension agreed on during the handshake.
+#[derive(Debug, Clone, Copy)]
+struct DeflateConfig {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+impl DeflateConfig {
+    /// Picks the first permessage-deflate offer the server can honor.
+    fn negotiate(offers: &str) -> Option<DeflateConfig> {
+        offers.split(',').find_map(|offer| {
+            let mut params = offer.split(';').map(|x| x.trim());
+
+            if params.next() != Some("permessage-deflate") {
+                return None;
+            }
+
+            let mut config = DeflateConfig {
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+            };
+
+            for param in params {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+
+                match (name, value) {
+                    ("server_no_context_takeover", None) => {
+                        config.server_no_context_takeover = true
+                    }
+                    ("client_no_context_takeover", None) => {
+                        config.client_no_context_takeover = true
+                    }
+                    // The client may always use a smaller window than the server decodes with
+                    ("client_max_window_bits", _) => {}
+                    // Compressing with a reduced window is not supported
+                    ("server_max_window_bits", Some("15")) => {}
+                    _ => return None,
+                }
+            }
+
+            Some(config)
+        })
+    }
+
+    fn response_header(&self) -> String {
+        let mut header = "permessage-deflate".to_string();
+
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+
+        header
+    }
+}
+
+/// An established WebSocket connection.
+///
+/// Use [`WebSocket::split`] to receive and send messages from different tasks.
+pub struct WebSocket {
+    reader: WebSocketReader,
+}
+
+impl WebSocket {
+    fn new(upgraded: Upgraded, deflate: Option<DeflateConfig>) -> WebSocket {
+        let (read_half, write_half) = split(upgraded);
+
+        let writer = WebSocketWriter {
+            state: Arc::new(Mutex::new(WriterState {
+                stream: write_half,
+                compressor: deflate.map(|config| {
+                    (
+                        Compress::new(Compression::default(), false),
+                        config.server_no_context_takeover,
+                    )
+                }),
+                closed: false,
+            })),
+        };
+
+        WebSocket {
+            reader: WebSocketReader {
+                stream: BufReader::new(read_half),
+                decompressor: deflate.map(|_| Decompress::new(false)),
+                writer,
+            },
+        }
+    }
+
+    /// Receives the next message, or `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        self.reader.recv().await
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        self.reader.writer.send(message).await
+    }
+
+    pub fn writer(&self) -> WebSocketWriter {
+        self.reader.writer.clone()
+    }
+
+    pub fn split(self) -> (WebSocketReader, WebSocketWriter) {
+        let writer = self.reader.writer.clone();
+        (self.reader, writer)
+    }
+}
+
+/// The receiving half of a WebSocket. Pings are answered and close handshakes completed
+/// automatically while receiving.
+pub struct WebSocketReader {
+    stream: BufReader<ReadHalf<Upgraded>>,
+    decompressor: Option<Decompress>,
+    writer: WebSocketWriter,
+}
+
+struct Frame {
+    fin: bool,
+    compressed: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WebSocketReader {
+    /// Receives the next message, or `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        let mut message: Option<(u8, bool, Vec<u8>)> = None;
+
+        loop {
+            let frame = match self.read_frame().await {
+                Ok(frame) => frame,
+                // The peer went away without a close handshake
+                Err(e) if is_eof(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+            match frame.opcode {
+                OPCODE_PING => self.writer.send_frame(OPCODE_PONG, &frame.payload).await?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    // Echo the status code back to complete the close handshake
+                    let code = match frame.payload[..] {
+                        [high, low, ..] => u16::from_be_bytes([high, low]),
+                        _ => CLOSE_NORMAL,
+                    };
+                    self.writer.close(code, "").await?;
+
+                    return Ok(None);
+                }
+                OPCODE_TEXT | OPCODE_BINARY if message.is_none() => {
+                    message = Some((frame.opcode, frame.compressed, frame.payload));
+                }
+                OPCODE_CONTINUATION if message.is_some() && !frame.compressed => {
+                    let (_, _, payload) = message.as_mut().unwrap();
+                    payload.extend_from_slice(&frame.payload);
+                }
+                _ => return self.fail(CLOSE_PROTOCOL_ERROR, "Unexpected frame").await,
+            }
+
+            if let Some((_, _, payload)) = &message {
+                if payload.len() > MAX_MESSAGE_SIZE {
+                    return self.fail(CLOSE_TOO_BIG, "Message too big").await;
+                }
+            }
+
+            // Control frames can be interleaved with the fragments of a message
+            if frame.fin && frame.opcode < OPCODE_CLOSE {
+                let (opcode, compressed, payload) = message.take().unwrap();
+
+                let payload = match compressed {
+                    true => match self.inflate(payload) {
+                        Ok(Some(payload)) => payload,
+                        Ok(None) => return self.fail(CLOSE_TOO_BIG, "Message too big").await,
+                        Err(_) => {
+                            return self
+                                .fail(CLOSE_INVALID_DATA, "Invalid compressed data")
+                                .await
+                        }
+                    },
+                    false => payload,
+                };
+
+                return match opcode {
+                    OPCODE_TEXT => match String::from_utf8(payload) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => self.fail(CLOSE_INVALID_DATA, "Invalid UTF-8").await,
+                    },
+                    _ => Ok(Some(Message::Binary(payload))),
+                };
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame> {
+        let mut header = [0; 2];
+        self.stream.read_exact(&mut header).await?;
+
+        let fin = header[0] & 0x80 != 0;
+        let compressed = header[0] & 0x40 != 0;
+        let opcode = header[0] & 0x0f;
+        let masked = header[1] & 0x80 != 0;
+
+        let len = match header[1] & 0x7f {
+            126 => self.stream.read_u16().await? as u64,
+            127 => self.stream.read_u64().await?,
+            len => len as u64,
+        };
+
+        // Clients must mask their frames, and only use RSV1 for compressed messages
+        if !masked || header[0] & 0x30 != 0 || (compressed && self.decompressor.is_none()) {
+            return self
+                .fail(CLOSE_PROTOCOL_ERROR, "Invalid frame header")
+                .await;
+        }
+
+        // Control frames cannot be fragmented nor carry more than 125 bytes
+        if opcode >= OPCODE_CLOSE && (!fin || len > 125 || compressed) {
+            return self
+                .fail(CLOSE_PROTOCOL_ERROR, "Invalid control frame")
+                .await;
+        }
+
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return self.fail(CLOSE_TOO_BIG, "Message too big").await;
+        }
+
+        let mut mask = [0; 4];
+        self.stream.read_exact(&mut mask).await?;
+
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload).await?;
+
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Frame {
+            fin,
+            compressed,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Decompresses a permessage-deflate message, or returns `None` if it is too big.
+    fn inflate(&mut self, mut payload: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let decompressor = self
+            .decompressor
+            .as_mut()
+            .ok_or(anyhow!("Compression was not negotiated"))?;
+
+        payload.extend_from_slice(&DEFLATE_TRAILER);
+
+        let mut output = Vec::with_capacity(payload.len() * 2);
+        let mut input = &payload[..];
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let total_in = decompressor.total_in();
+            decompressor.decompress_vec(input, &mut output, FlushDecompress::Sync)?;
+            input = &input[(decompressor.total_in() - total_in) as usize..];
+
+            if output.len() > MAX_MESSAGE_SIZE {
+                return Ok(None);
+            }
+
+            // Done once all the input is consumed without filling the output buffer
+            if input.is_empty() && output.len() < output.capacity() {
+                return Ok(Some(output));
+            }
+        }
+    }
+
+    /// Closes the connection with the given status code and returns an error describing why.
+    async fn fail<T>(&mut self, code: u16, reason: &str) -> Result<T> {
+        self.writer.close(code, reason).await?;
+
+        Err(anyhow!("WebSocket closed: {}", reason))
+    }
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+struct WriterState {
+    stream: WriteHalf<Upgraded>,
+    /// Compressor for outgoing messages, and whether it is reset after each message.
+    compressor: Option<(Compress, bool)>,
+    closed: bool,
+}
+
+/// The sending half of a WebSocket. It can be cloned to send from several tasks.
+#[derive(Clone)]
+pub struct WebSocketWriter {
+    state: Arc<Mutex<WriterState>>,
+}
+
+impl WebSocketWriter {
+    pub async fn send(&self, message: Message) -> Result<()> {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (OPCODE_TEXT, text.into_bytes()),
+            Message::Binary(data) => (OPCODE_BINARY, data),
+        };
+
+        let mut state = self.state.lock().await;
+
+        if state.closed {
+            return Err(anyhow!("WebSocket is closed"));
+        }
+
+        match state.compressor.as_mut() {
+            Some((compressor, no_context_takeover)) => {
+                if *no_context_takeover {
+                    compressor.reset();
+                }
+
+                let payload = deflate(compressor, &payload)?;
+                write_frame(&mut state.stream, opcode, true, &payload).await
+            }
+            None => write_frame(&mut state.stream, opcode, false, &payload).await,
+        }
+    }
+
+    pub async fn ping(&self, payload: &[u8]) -> Result<()> {
+        self.send_frame(OPCODE_PING, payload).await
+    }
+
+    /// Starts the close handshake. Does nothing if the connection is already closed.
+    pub async fn close(&self, code: u16, reason: &str) -> Result<()> {
+        let mut state = self.state.lock().await;
+
+        if state.closed {
+            return Ok(());
+        }
+        state.closed = true;
+
+        let mut payload = code.to_be_bytes().to_vec();
+        // Control frame payloads are limited to 125 bytes, 2 of which hold the code
+        payload.extend(reason.bytes().take(123));
+
+        write_frame(&mut state.stream, OPCODE_CLOSE, false, &payload).await?;
+        state.stream.flush().await?;
+
+        Ok(())
+    }
+
+    async fn send_frame(&self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().await;
+
+        if state.closed {
+            return Ok(());
+        }
+
+        write_frame(&mut state.stream, opcode, false, payload).await
+    }
+}
+
+async fn write_frame(
+    stream: &mut WriteHalf<Upgraded>,
+    opcode: u8,
+    compressed: bool,
+    payload: &[u8],
+) -> Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+
+    frame.push(0x80 | if compressed { 0x40 } else { 0 } | opcode);
+
+    // Server frames are never masked
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+fn deflate(compressor: &mut Compress, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(payload.len() / 2 + 64);
+    let mut input = payload;
+
+    loop {
+        if output.len() == output.capacity() {
+            output.reserve(output.capacity());
+        }
+
+        let total_in = compressor.total_in();
+        compressor.compress_vec(input, &mut output, FlushCompress::Sync)?;
+        input = &input[(compressor.total_in() - total_in) as usize..];
+
+        // The sync flush is complete once the compressor stops filling the output buffer
+        if input.is_empty() && output.len() < output.capacity() {
+            break;
+        }
+    }
+
+    if output.ends_with(&DEFLATE_TRAILER) {
+        output.truncate(output.len() - DEFLATE_TRAILER.len());
+    }
+
+    Ok(output)
+}